@@ -14,14 +14,21 @@
 // You should have received a copy of the GNU General Public License
 // along with subpub.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::external;
-use anyhow::{anyhow, Context};
+use crate::{
+	external::{
+		cargo_metadata::{DependencyKind, Package},
+		KnownVersions, Registry,
+	},
+	manifest_backups::ManifestBackups,
+	staging::StagingArea,
+};
+use anyhow::Context;
 use semver::Version;
 use std::{
-	cell::{Ref, RefCell},
 	collections::HashSet,
 	io::{Cursor, Read},
 	path::{Path, PathBuf},
+	sync::OnceLock,
 };
 
 #[derive(Debug, Clone)]
@@ -31,62 +38,61 @@ pub struct CrateDetails {
 	pub deps: HashSet<String>,
 	pub build_deps: HashSet<String>,
 	pub dev_deps: HashSet<String>,
-	/// Known versions from crates.io.
+	/// Known versions from the registry.
 	pub known_versions: CrateVersions,
+	/// The registries this crate may be published to, or `None` for any of them.
+	pub publish: Option<Vec<String>>,
 
 	// Modifying the files on disk can only be done through the interface below.
 	toml_path: PathBuf,
+	// The root manifest of the workspace, which values can be inherited from.
+	workspace_toml_path: PathBuf,
 }
 
 impl CrateDetails {
-	/// Read a Cargo.toml file, pulling out the information we care about.
-	pub fn load(path: PathBuf) -> anyhow::Result<CrateDetails> {
-		let val: toml_edit::Document = read_toml(&path)?;
-
-		let name = val
-			.get("package")
-			.ok_or_else(|| anyhow!("Cannot read [package] section from toml file."))?
-			.get("name")
-			.ok_or_else(|| anyhow!("Cannot read package.name from toml file."))?
-			.as_str()
-			.ok_or_else(|| anyhow!("package.name is not a string, but should be."))?
-			.to_owned();
-
-		let version = val
-			.get("package")
-			.ok_or_else(|| anyhow!("Cannot read [package] section from {name}."))?
-			.get("version")
-			.ok_or_else(|| anyhow!("Cannot read package.version from {name}."))?
-			.as_str()
-			.ok_or_else(|| anyhow!("Cannot read package.version from {name}."))?
-			.to_owned();
-
-		let version = Version::parse(&version)
-			.with_context(|| format!("Cannot parse SemVer compatible version from {name}"))?;
-
+	/// Pull out the information we care about from what `cargo metadata` says about a package.
+	/// Only dependencies on other packages in `members` (the workspace) are noted.
+	pub fn load(package: &Package, members: &HashSet<&str>, workspace_root: &Path) -> CrateDetails {
 		let mut build_deps = HashSet::new();
 		let mut dev_deps = HashSet::new();
 		let mut deps = HashSet::new();
 
-		for item in get_all_dependency_sections(&val, "build-dependencies") {
-			build_deps.extend(filter_workspace_dependencies(item)?);
-		}
-		for item in get_all_dependency_sections(&val, "dev-dependencies") {
-			dev_deps.extend(filter_workspace_dependencies(item)?);
-		}
-		for item in get_all_dependency_sections(&val, "dependencies") {
-			deps.extend(filter_workspace_dependencies(item)?);
+		let workspace_deps = package
+			.dependencies
+			.iter()
+			.filter(|dep| dep.path.is_some() && members.contains(&*dep.name));
+		for dep in workspace_deps {
+			let name = dep.name.clone();
+			match dep.kind {
+				Some(DependencyKind::Build) => build_deps.insert(name),
+				Some(DependencyKind::Dev) => dev_deps.insert(name),
+				None => deps.insert(name),
+			};
 		}
 
-		Ok(CrateDetails {
-			name: name.clone(),
-			version,
+		CrateDetails {
+			name: package.name.clone(),
+			version: package.version.clone(),
 			deps,
 			dev_deps,
 			build_deps,
-			known_versions: CrateVersions::new(name),
-			toml_path: path,
-		})
+			known_versions: CrateVersions::new(package.name.clone()),
+			publish: package.publish.clone(),
+			toml_path: package.manifest_path.clone(),
+			workspace_toml_path: workspace_root.join("Cargo.toml"),
+		}
+	}
+
+	/// Can this crate be published to the registry given (by its name in the cargo config), or to
+	/// crates.io if `None`?
+	pub fn can_publish_to(&self, registry_name: Option<&str>) -> bool {
+		match &self.publish {
+			Some(registries) => {
+				let registry_name = registry_name.unwrap_or("crates-io");
+				registries.iter().any(|r| r == registry_name)
+			},
+			None => true,
+		}
 	}
 
 	/// Bump the version for a breaking change and to release. Examples of bumps carried out:
@@ -98,12 +104,28 @@ impl CrateDetails {
 	/// 4.0.0+buildmetadata -> 5.0.0+buildmetadata (preserve build metadata regardless)
 	/// ```
 	///
-	/// Return the old and new version.
+	/// If the version is inherited from the workspace, then it's updated in `[workspace.package]`
+	/// instead, which changes the version of every other crate that inherits it too.
 	pub fn write_own_version(&mut self, version: Version) -> anyhow::Result<()> {
 		// Load TOML file and update the version in that.
 		let mut toml = self.read_toml()?;
-		toml["package"]["version"] = toml_edit::value(version.to_string());
-		self.write_toml(&toml)?;
+		let inherited = match toml.get("package").and_then(|p| p.get("version")) {
+			Some(version) => is_inherited(version),
+			None => anyhow::bail!(
+				"Crate '{}' has no version in {:?} to bump",
+				self.name,
+				self.toml_path
+			),
+		};
+		if inherited {
+			let mut workspace_toml = read_toml(&self.workspace_toml_path)?;
+			workspace_toml["workspace"]["package"]["version"] =
+				toml_edit::value(version.to_string());
+			write_toml(&self.workspace_toml_path, &workspace_toml)?;
+		} else {
+			toml["package"]["version"] = toml_edit::value(version.to_string());
+			self.write_toml(&toml)?;
+		}
 
 		// If that worked, save the in-memory version too
 		self.version = version;
@@ -111,7 +133,15 @@ impl CrateDetails {
 		Ok(())
 	}
 
-	/// Set any references to the dependency provided to the version given.
+	/// Is this crate's version inherited from `[workspace.package]`?
+	pub fn inherits_version(&self) -> anyhow::Result<bool> {
+		let toml = self.read_toml()?;
+		let version = toml.get("package").and_then(|p| p.get("version"));
+		Ok(version.map(is_inherited).unwrap_or(false))
+	}
+
+	/// Set any references to the dependency provided to the version given. Dependencies which are
+	/// inherited from the workspace are left alone; see [`write_workspace_dependency_version`].
 	pub fn write_dependency_version(
 		&self,
 		dependency: &str,
@@ -126,80 +156,63 @@ impl CrateDetails {
 
 		let mut toml = self.read_toml()?;
 
-		fn do_set(item: &mut toml_edit::Item, version: &Version, dependency: &str) {
-			let table = match item.as_table_like_mut() {
-				Some(table) => table,
-				None => return,
-			};
-
-			let dep = match table.get_mut(dependency) {
-				Some(dep) => dep,
-				None => return,
-			};
-
-			if dep.is_str() {
-				// Set version if it's just a string
-				*dep = toml_edit::value(version.to_string());
-			} else if let Some(table) = dep.as_table_like_mut() {
-				// If table, only update version if version is present
-				if let Some(v) = table.get_mut("version") {
-					*v = toml_edit::value(version.to_string());
-				}
-			}
+		for label in ["build-dependencies", "dev-dependencies", "dependencies"] {
+			edit_all_dependency_sections(&mut toml, label, |item| {
+				set_dependency_version(item, version, dependency);
+			});
 		}
 
-		edit_all_dependency_sections(&mut toml, "build-dependencies", |item| {
-			do_set(item, version, dependency)
-		});
-		edit_all_dependency_sections(&mut toml, "dev-dependencies", |item| {
-			do_set(item, version, dependency)
-		});
-		edit_all_dependency_sections(&mut toml, "dependencies", |item| {
-			do_set(item, version, dependency)
-		});
-
 		self.write_toml(&toml)?;
 
 		Ok(true)
 	}
 
-	/// Strip dev dependencies.
-	pub fn strip_dev_deps(&self) -> anyhow::Result<()> {
+	/// Strip dev dependencies, first taking a backup of the manifest so that it can be restored.
+	pub fn strip_dev_deps(&self, backups: &ManifestBackups) -> anyhow::Result<()> {
+		backups.save(&self.name, &self.toml_path)?;
 		let mut toml = self.read_toml()?;
 
-		// Remove [dev-dependencies]
-		let removed_top_level = toml.remove("dev-dependencies").is_some();
-		// Remove [target.X.dev-dependencies]
-		let removed_target_deps = toml
-			.get_mut("target")
-			.and_then(|item| item.as_table_like_mut())
-			.into_iter()
-			.flat_map(|table| table.iter_mut())
-			.flat_map(|(_, item)| item.as_table_like_mut())
-			.fold(false, |is_removed, t| t.remove("dev-dependencies").is_some() || is_removed);
-
 		// Only write the toml file back if we did remove something.
-		if removed_top_level || removed_target_deps {
+		if strip_dev_dependencies(&mut toml) {
 			self.write_toml(&toml)?;
 		}
 
 		Ok(())
 	}
 
-	/// Publish the current code for this crate as-is. You may want to run
+	/// Publish the current code for this crate as-is to the registry given. You may want to run
 	/// [`CrateDetails::strip_dev_deps()`] first.
-	pub fn publish(&self) -> anyhow::Result<()> {
+	pub fn publish(&self, registry: &dyn Registry) -> anyhow::Result<()> {
 		let parent = self.toml_path.parent().expect("parent of toml path should exist");
-		external::cargo::publish_crate(parent, &self.name)
+		registry.publish_crate(parent, &self.name, &self.version, true)
+	}
+
+	/// The directory that this crate lives in.
+	pub fn dir(&self) -> &Path {
+		self.toml_path.parent().expect("parent of toml path should exist")
+	}
+
+	/// Copy this crate, with its dev dependencies stripped, into the staging area given so that
+	/// it can be published without touching the workspace. Returns the directory that the crate
+	/// was staged in.
+	pub fn stage(&self, staging: &StagingArea) -> anyhow::Result<PathBuf> {
+		staging.stage(self.dir(), &self.workspace_toml_path, &self.name, &self.version)
 	}
 
 	/// This checks whether we actually need to publish a new version of the crate. It'll return
-	/// `false` only if, as far as we can see, the current version is published to crates.io, and
-	/// there have been no changes to it since.
-	pub fn needs_publishing(&self) -> anyhow::Result<bool> {
+	/// `false` only if, as far as we can see, the current version is published to the registry,
+	/// has not been yanked, and there have been no changes to it since.
+	pub fn needs_publishing(&self, registry: &dyn Registry) -> anyhow::Result<bool> {
 		let name = &self.name;
 
-		let crate_bytes = external::crates_io::try_download_crate(&self.name, &self.version)
+		// Nobody can depend on a yanked version any more, so we'll need to publish a new one.
+		if self.known_versions.get(registry)?.is_yanked(&self.version) {
+			log::debug!("{name}: the current version {} has been yanked", self.version);
+			return Ok(true)
+		}
+
+		let crate_bytes = registry
+			.try_download_crate(&self.name, &self.version)
 			.with_context(|| format!("Could not download crate {name}"))?;
 
 		let crate_bytes = match crate_bytes {
@@ -282,15 +295,18 @@ impl CrateDetails {
 	}
 
 	/// Does this create need a version bump in order to be published?
-	pub fn needs_version_bump_to_publish(&mut self) -> anyhow::Result<bool> {
+	pub fn needs_version_bump_to_publish(
+		&mut self,
+		registry: &dyn Registry,
+	) -> anyhow::Result<bool> {
 		if self.version.pre != semver::Prerelease::EMPTY {
 			// If prerelease eg `-dev`, we'll want to bump.
 			return Ok(true)
 		}
 
-		// Does the current version of this crate exist on crates.io?
+		// Does the current version of this crate exist in the registry?
 		// If so, we need to bump the current version.
-		let known_versions = self.known_versions.get()?;
+		let known_versions = self.known_versions.get(registry)?;
 		Ok(known_versions.contains(&self.version))
 	}
 
@@ -306,29 +322,210 @@ impl CrateDetails {
 	}
 }
 
-/// An iterator that hands back all "dependencies"/"dev-dependencies"/"build-dependencies"
-/// (according to the label provided), by looking in the top level `[label]` section as well as any
-/// `[target.'foo'.label]` sections.
-fn get_all_dependency_sections<'a>(
-	document: &'a toml_edit::Document,
-	label: &'a str,
-) -> impl Iterator<Item = &'a toml_edit::Item> + 'a {
-	let target = document
-		.get("target")
-		.and_then(|t| t.as_table_like())
-		.into_iter()
-		.flat_map(|t| {
-			// For each item of the "target" table, see if we can find a `label` section in it.
-			t.iter()
-				.flat_map(|(_name, item)| item.as_table_like())
-				.flat_map(|t| t.get(label))
+/// Set the version of the dependency provided in `[workspace.dependencies]` of the workspace
+/// manifest given, if it's there. Returns whether anything was changed.
+pub fn write_workspace_dependency_version(
+	workspace_toml_path: &Path,
+	dependency: &str,
+	version: &Version,
+) -> anyhow::Result<bool> {
+	let mut toml = read_toml(workspace_toml_path)?;
+	let deps = match toml.get_mut("workspace").and_then(|w| w.get_mut("dependencies")) {
+		Some(deps) => deps,
+		None => return Ok(false),
+	};
+	if !set_dependency_version(deps, version, dependency) {
+		return Ok(false)
+	}
+
+	write_toml(workspace_toml_path, &toml)?;
+	Ok(true)
+}
+
+/// Set the version of the dependency provided in a single dependency section, returning whether
+/// anything was changed. Dependencies are matched by the package that they refer to, so renamed
+/// dependencies like `codec = { package = "parity-scale-codec", ... }` are found by their package
+/// name, and a crate can be depended on under more than one name.
+fn set_dependency_version(item: &mut toml_edit::Item, version: &Version, dependency: &str) -> bool {
+	let table = match item.as_table_like_mut() {
+		Some(table) => table,
+		None => return false,
+	};
+
+	let mut changed = false;
+	for (key, dep) in table.iter_mut() {
+		let package = dep.get("package").and_then(|p| p.as_str()).unwrap_or(&key);
+		if package != dependency {
+			continue
+		}
+
+		if dep.is_str() {
+			// Set version if it's just a string
+			*dep = toml_edit::value(version.to_string());
+			changed = true;
+		} else if let Some(table) = dep.as_table_like_mut() {
+			// If table, only update version if version is present
+			if let Some(v) = table.get_mut("version") {
+				*v = toml_edit::value(version.to_string());
+				changed = true;
+			}
+		}
+	}
+	changed
+}
+
+/// Is this a value like `version.workspace = true`, which is inherited from the workspace?
+fn is_inherited(item: &toml_edit::Item) -> bool {
+	item.get("workspace").and_then(|w| w.as_bool()).unwrap_or(false)
+}
+
+/// Replace anything that a manifest inherits from the workspace with the values from the
+/// workspace manifest given, so that it no longer needs the workspace. Any paths taken from the
+/// workspace are made absolute, since they are relative to the workspace root.
+pub fn inherit_workspace_fields(
+	toml: &mut toml_edit::Document,
+	workspace_toml: &toml_edit::Document,
+	workspace_root: &Path,
+) -> anyhow::Result<()> {
+	let workspace = workspace_toml.get("workspace");
+	let mut missing = vec![];
+
+	// `[package]` fields, like `version.workspace = true`.
+	if let Some(package) = toml.get_mut("package").and_then(|p| p.as_table_like_mut()) {
+		for (mut key, item) in package.iter_mut() {
+			if !is_inherited(item) {
+				continue
+			}
+			let inherited =
+				match workspace.and_then(|w| w.get("package")).and_then(|p| p.get(&*key)) {
+					Some(inherited) => inherited,
+					None => {
+						missing.push(format!("workspace.package.{key}"));
+						continue
+					},
+				};
+			*item = match inherited.as_str() {
+				Some(relative) if key == "readme" || key == "license-file" => {
+					let absolute = workspace_root.join(relative);
+					toml_edit::value(absolute.to_string_lossy().into_owned())
+				},
+				_ => inherited.clone(),
+			};
+			// `version.workspace = true` leaves the key without the usual space after it.
+			key.fmt();
+			if let Some(value) = item.as_value_mut() {
+				value.decor_mut().clear();
+			}
+		}
+	}
+
+	// Dependencies, like `foo = { workspace = true, features = ["bar"] }`.
+	let workspace_deps = workspace.and_then(|w| w.get("dependencies"));
+	for label in ["build-dependencies", "dev-dependencies", "dependencies"] {
+		edit_all_dependency_sections(toml, label, |item| {
+			let table = match item.as_table_like_mut() {
+				Some(table) => table,
+				None => return,
+			};
+			for (mut name, dep) in table.iter_mut() {
+				if !is_inherited(dep) {
+					continue
+				}
+				match workspace_deps.and_then(|deps| deps.get(&*name)) {
+					Some(inherited) => inherit_dependency(dep, inherited, workspace_root),
+					None => missing.push(format!("workspace.dependencies.{name}")),
+				}
+				// As with `[package]` fields, `foo.workspace = true` leaves no space after the key.
+				name.fmt();
+			}
 		});
+	}
+
+	// `[lints]`, which can only be inherited as a whole.
+	if toml.get("lints").map(is_inherited).unwrap_or(false) {
+		match workspace.and_then(|w| w.get("lints")) {
+			Some(lints) => toml["lints"] = lints.clone(),
+			None => missing.push("workspace.lints".to_owned()),
+		}
+	}
+
+	if !missing.is_empty() {
+		anyhow::bail!(
+			"Values are inherited from the workspace, but the workspace does not set {}",
+			missing.join(", ")
+		);
+	}
+	Ok(())
+}
+
+/// Replace a dependency of the form `foo = { workspace = true, ... }` with the dependency from
+/// `[workspace.dependencies]` given, keeping anything else that it sets.
+fn inherit_dependency(
+	dep: &mut toml_edit::Item,
+	inherited: &toml_edit::Item,
+	workspace_root: &Path,
+) {
+	let mut resolved = toml_edit::InlineTable::new();
+	if let Some(version) = inherited.as_str() {
+		resolved.insert("version", version.into());
+	} else if let Some(table) = inherited.as_table_like() {
+		for (key, value) in table.iter() {
+			let value = match value.as_value() {
+				Some(value) => value.clone(),
+				None => continue,
+			};
+			match value.as_str() {
+				Some(relative) if key == "path" => {
+					let absolute = workspace_root.join(relative);
+					resolved.insert(key, absolute.to_string_lossy().into_owned().into());
+				},
+				_ => {
+					resolved.insert(key, value);
+				},
+			}
+		}
+	}
 
-	document.get(label).into_iter().chain(target)
+	let overrides = match dep.as_table_like() {
+		Some(overrides) => overrides,
+		None => return,
+	};
+	for (key, value) in overrides.iter() {
+		let value = match value.as_value() {
+			Some(value) => value,
+			None => continue,
+		};
+		match key {
+			"workspace" => {},
+			// Features are added to those that the workspace asks for.
+			"features" => {
+				let mut features = resolved
+					.get("features")
+					.and_then(|f| f.as_array())
+					.cloned()
+					.unwrap_or_default();
+				for feature in value.as_array().into_iter().flatten() {
+					features.push(feature.clone());
+				}
+				resolved.insert("features", features.into());
+			},
+			_ => {
+				resolved.insert(key, value.clone());
+			},
+		}
+	}
+
+	// A `[dependencies.foo]` section stays a section, but `foo.workspace = true` is only a table
+	// because of its dotted key, and reads better as an inline table like the others.
+	resolved.fmt();
+	*dep = match dep.as_table() {
+		Some(table) if !table.is_dotted() => toml_edit::Item::Table(resolved.into_table()),
+		_ => toml_edit::value(resolved),
+	};
 }
 
-/// Similar to `get_all_dependencies`, but mutable iterates over just `[target.'foo'.label]`
-/// sections.
+/// An iterator that mutably hands back the "dependencies"/"dev-dependencies"/"build-dependencies"
+/// (according to the label provided) in any `[target.'foo'.label]` sections.
 fn get_target_dependency_sections_mut<'a>(
 	document: &'a mut toml_edit::Document,
 	label: &'a str,
@@ -360,8 +557,52 @@ fn edit_all_dependency_sections<F: FnMut(&mut toml_edit::Item)>(
 	}
 }
 
+/// Remove any `[dev-dependencies]` and `[target.X.dev-dependencies]` sections from a manifest,
+/// returning whether there were any to remove.
+pub fn strip_dev_dependencies(toml: &mut toml_edit::Document) -> bool {
+	// Remove [dev-dependencies]
+	let removed_top_level = toml.remove("dev-dependencies").is_some();
+	// Remove [target.X.dev-dependencies]. This has to visit every target, so `any` won't do.
+	#[allow(clippy::unnecessary_fold)]
+	let removed_target_deps = toml
+		.get_mut("target")
+		.and_then(|item| item.as_table_like_mut())
+		.into_iter()
+		.flat_map(|table| table.iter_mut())
+		.flat_map(|(_, item)| item.as_table_like_mut())
+		.fold(false, |is_removed, t| t.remove("dev-dependencies").is_some() || is_removed);
+
+	removed_top_level || removed_target_deps
+}
+
+/// Point any path dependencies in a manifest at absolute paths, resolving relative paths against
+/// `crate_root`, so that the manifest can be moved elsewhere.
+pub fn make_dependency_paths_absolute(toml: &mut toml_edit::Document, crate_root: &Path) {
+	fn do_set(item: &mut toml_edit::Item, crate_root: &Path) {
+		let table = match item.as_table_like_mut() {
+			Some(table) => table,
+			None => return,
+		};
+
+		for (_name, dep) in table.iter_mut() {
+			let path = match dep.get_mut("path") {
+				Some(path) => path,
+				None => continue,
+			};
+			if let Some(relative) = path.as_str() {
+				let absolute = crate_root.join(relative);
+				*path = toml_edit::value(absolute.to_string_lossy().into_owned());
+			}
+		}
+	}
+
+	for label in ["build-dependencies", "dev-dependencies", "dependencies"] {
+		edit_all_dependency_sections(toml, label, |item| do_set(item, crate_root));
+	}
+}
+
 // Load a TOML file.
-fn read_toml(path: &Path) -> anyhow::Result<toml_edit::Document> {
+pub fn read_toml(path: &Path) -> anyhow::Result<toml_edit::Document> {
 	let toml_string = std::fs::read_to_string(path)
 		.with_context(|| format!("Cannot read the Cargo.toml at {path:?}"))?;
 	let toml = toml_string
@@ -370,49 +611,11 @@ fn read_toml(path: &Path) -> anyhow::Result<toml_edit::Document> {
 	Ok(toml)
 }
 
-/// Given a path to some dependencies in a TOML file, pull out the package names
-/// for any path based dependencies (ie dependencies in the same workspace).
-fn filter_workspace_dependencies(val: &toml_edit::Item) -> anyhow::Result<HashSet<String>> {
-	let arr = match val.as_table() {
-		Some(arr) => arr,
-		None => return Err(anyhow!("dependencies should be a TOML table.")),
-	};
-
-	let mut deps = HashSet::new();
-	for (name, props) in arr {
-		// If props arent a table eg { path = "/foo" }, this is
-		// not a workspace dependency (since it needs a "path" prop)
-		// so skip over it.
-		let props = match props.as_table_like() {
-			Some(props) => props,
-			None => continue,
-		};
-
-		// Ignore any dependency without a "path" (not a workspace dep
-		// if it doesn't point to another crate via a path).
-		let path = match props.get("path") {
-			Some(path) => path,
-			None => continue,
-		};
-
-		// Expect path to be a string. Error if it's not.
-		path.as_str().ok_or_else(|| anyhow!("{}.path is not a string.", name))?;
-
-		// What is the actual package name?
-		let package_name = props
-			.get("package")
-			.map(|package| {
-				package
-					.as_str()
-					.map(|s| s.to_string())
-					.ok_or_else(|| anyhow!("{}.package is not a string.", name))
-			})
-			.unwrap_or(Ok(name.to_string()))?;
-
-		deps.insert(package_name);
-	}
-
-	Ok(deps)
+// Save a TOML file.
+fn write_toml(path: &Path, toml: &toml_edit::Document) -> anyhow::Result<()> {
+	std::fs::write(path, toml.to_string())
+		.with_context(|| format!("Cannot save the updated Cargo.toml at {path:?}"))?;
+	Ok(())
 }
 
 /// Compare the content of 2 readers, returning whether they are equal or not.
@@ -436,7 +639,7 @@ fn are_contents_equal<A: Read, B: Read>(mut a: A, mut b: B) -> anyhow::Result<bo
 #[derive(Debug, Clone)]
 pub struct CrateVersions {
 	name: String,
-	versions: RefCell<Option<HashSet<Version>>>,
+	versions: OnceLock<KnownVersions>,
 }
 
 impl CrateVersions {
@@ -444,18 +647,147 @@ impl CrateVersions {
 		CrateVersions { name, versions: Default::default() }
 	}
 
-	/// Get the versions from crates.io.
-	pub fn get(&self) -> anyhow::Result<Ref<'_, HashSet<Version>>> {
-		// Ensure the scope of `RefMut` does not overlap with `Ref` below.
-		{
-			let mut versions = self.versions.borrow_mut();
-			if versions.is_none() {
-				*versions = Some(external::crates_io::get_known_crate_versions(&self.name)?);
-			}
+	/// Get the versions from the registry. These are only fetched once, and this is safe to call
+	/// from several threads at once.
+	pub fn get(&self, registry: &dyn Registry) -> anyhow::Result<&KnownVersions> {
+		if let Some(versions) = self.versions.get() {
+			return Ok(versions)
 		}
 
-		Ok(Ref::map(self.versions.borrow(), |opt| {
-			opt.as_ref().expect("versions populated above; qed")
-		}))
+		// If another thread beats us to it, we'll end up using their versions instead.
+		let versions = registry.get_known_crate_versions(&self.name)?;
+		Ok(self.versions.get_or_init(|| versions))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(toml: &str) -> toml_edit::Document {
+		toml.parse().unwrap()
+	}
+
+	#[test]
+	fn every_rename_of_a_dependency_is_bumped() {
+		let mut toml = parse(
+			r#"[dependencies]
+codec = { package = "parity-scale-codec", version = "3.0.0" }
+scale = { package = "parity-scale-codec", version = "3.0.0", default-features = false }
+parity-scale-codec = "3.0.0"
+other = { package = "other-codec", version = "3.0.0" }
+local = { package = "parity-scale-codec", path = "../codec" }
+"#,
+		);
+
+		let version = Version::new(3, 1, 0);
+		assert!(set_dependency_version(&mut toml["dependencies"], &version, "parity-scale-codec"));
+		assert_eq!(
+			toml.to_string(),
+			r#"[dependencies]
+codec = { package = "parity-scale-codec", version = "3.1.0" }
+scale = { package = "parity-scale-codec", version = "3.1.0", default-features = false }
+parity-scale-codec = "3.1.0"
+other = { package = "other-codec", version = "3.0.0" }
+local = { package = "parity-scale-codec", path = "../codec" }
+"#
+		);
+	}
+
+	#[test]
+	fn a_dependency_named_like_another_package_is_not_bumped() {
+		let mut toml = parse(
+			r#"[dependencies]
+parity-scale-codec = { package = "something-else", version = "1.0.0" }
+"#,
+		);
+
+		let version = Version::new(3, 1, 0);
+		assert!(!set_dependency_version(&mut toml["dependencies"], &version, "parity-scale-codec"));
+		assert_eq!(toml["dependencies"]["parity-scale-codec"]["version"].as_str(), Some("1.0.0"));
+	}
+
+	const WORKSPACE: &str = r#"[workspace]
+members = ["crates/*"]
+
+[workspace.package]
+version = "0.2.0"
+edition = "2021"
+readme = "README.md"
+
+[workspace.dependencies]
+serde = { version = "1.0.0", features = ["derive"] }
+log = "0.4.0"
+local = { path = "crates/local", version = "0.2.0" }
+"#;
+
+	#[test]
+	fn package_fields_are_inherited_from_the_workspace() {
+		let mut toml = parse(
+			r#"[package]
+name = "foo"
+version.workspace = true
+edition = { workspace = true }
+readme.workspace = true
+"#,
+		);
+
+		inherit_workspace_fields(&mut toml, &parse(WORKSPACE), Path::new("/ws")).unwrap();
+		assert_eq!(
+			toml.to_string(),
+			r#"[package]
+name = "foo"
+version = "0.2.0"
+edition = "2021"
+readme = "/ws/README.md"
+"#
+		);
+	}
+
+	#[test]
+	fn dependencies_are_inherited_from_the_workspace() {
+		let mut toml = parse(
+			r#"[dependencies]
+serde = { workspace = true, features = ["rc"], optional = true }
+log.workspace = true
+local = { workspace = true }
+
+[dev-dependencies.serde]
+workspace = true
+"#,
+		);
+
+		inherit_workspace_fields(&mut toml, &parse(WORKSPACE), Path::new("/ws")).unwrap();
+		assert_eq!(
+			toml.to_string(),
+			r#"[dependencies]
+serde = { version = "1.0.0", features = ["derive", "rc"], optional = true }
+log = { version = "0.4.0" }
+local = { path = "/ws/crates/local", version = "0.2.0" }
+
+[dev-dependencies.serde]
+version = "1.0.0"
+features = ["derive"]
+"#
+		);
+	}
+
+	#[test]
+	fn everything_missing_from_the_workspace_is_reported() {
+		let mut toml = parse(
+			r#"[package]
+name = "foo"
+license.workspace = true
+
+[dependencies]
+missing = { workspace = true }
+"#,
+		);
+
+		let err = inherit_workspace_fields(&mut toml, &parse(WORKSPACE), Path::new("/ws"))
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("workspace.package.license"), "{err}");
+		assert!(err.contains("workspace.dependencies.missing"), "{err}");
 	}
 }
@@ -15,24 +15,64 @@
 // along with subpub.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-	crate_details::CrateDetails,
-	external,
+	crate_details::{self, CrateDetails},
+	external::{self, Registry},
+	journal::{CrateState, Journal},
+	manifest_backups::ManifestBackups,
+	parallel,
+	staging::StagingArea,
 	version::{bump_for_breaking_change, Version},
 };
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use std::{
-	collections::{HashMap, HashSet},
-	path::PathBuf,
+	collections::{BTreeMap, HashMap, HashSet},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
 };
-use walkdir::WalkDir;
+
+/// Where we look to see whether a crate that we've published has shown up yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitFor {
+	/// The registry index. This is what cargo resolves dependencies from, so crates depending
+	/// on the published crate can be published as soon as it's here.
+	Index,
+	/// The registry's web API.
+	Api,
+}
+
+/// How to wait for a crate to show up in the registry once it's been published.
+#[derive(Debug, Clone, Copy)]
+pub struct WaitOptions {
+	pub wait_for: WaitFor,
+	/// How long to wait between checks.
+	pub interval: Duration,
+	/// How long to wait in total before giving up.
+	pub timeout: Duration,
+}
+
+/// Where crates are published from.
+pub enum PublishFrom<'a> {
+	/// Strip dev dependencies from the manifests in the workspace itself, backing them up so
+	/// that they can be restored once published.
+	Workspace(&'a ManifestBackups),
+	/// Copy each crate into a staging area and strip it there, leaving the workspace untouched.
+	Staging(&'a StagingArea),
+}
 
 #[derive(Debug, Clone)]
 pub struct Crates {
 	root: PathBuf,
+	// Where crates are published to, and where we look for published crates.
+	registry: Arc<dyn Registry>,
 	// Details for a given crate, including dependencies.
 	details: HashMap<String, CrateDetails>,
 	// Which crates depend on a given crate.
 	dependees: HashMap<String, Dependees>,
+	// The root manifest of the workspace.
+	workspace_toml_path: PathBuf,
+	// Cargo's target directory for the workspace.
+	target_dir: PathBuf,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -43,29 +83,72 @@ struct Dependees {
 }
 
 impl Crates {
-	/// Return a map of all substrate crates, in the form `crate_name => ( path, details )`.
-	pub fn load_crates_in_workspace(root: PathBuf) -> anyhow::Result<Crates> {
-		// Load details:
-		let details = crate_cargo_tomls(root.clone())
-			.into_iter()
-			.map(|path| {
-				let details = CrateDetails::load(path)?;
-				Ok((details.name.clone(), details))
-			})
-			.collect::<anyhow::Result<HashMap<_, _>>>()?;
+	/// Load the details of every crate in the workspace, as cargo sees it.
+	pub fn load_crates_in_workspace(
+		root: PathBuf,
+		registry: Arc<dyn Registry>,
+	) -> anyhow::Result<Crates> {
+		// Cargo refuses to load a workspace with two packages of the same name, without saying
+		// where they are, so look for them ourselves first.
+		let mut names: HashMap<String, PathBuf> = HashMap::new();
+		for path in workspace_member_tomls(&root)? {
+			let toml = crate_details::read_toml(&path)?;
+			let name =
+				match toml.get("package").and_then(|p| p.get("name")).and_then(|n| n.as_str()) {
+					Some(name) => name.to_owned(),
+					None => continue,
+				};
+			if let Some(existing) = names.get(&name) {
+				anyhow::bail!(
+					"There are two crates named {name} in the workspace, at {existing:?} and {path:?}"
+				);
+			}
+			names.insert(name, path);
+		}
 
-		// Sanity check the details; make sure all listed dependencies exist.
-		for crate_details in details.values() {
-			for dep in &crate_details.deps {
-				if !details.contains_key(dep) {
-					let crate_name = &crate_details.name;
+		let metadata = external::cargo::workspace_metadata(&root)?;
+		let members: HashSet<&str> = metadata.packages.iter().map(|p| &*p.name).collect();
+
+		// Sanity check the packages; the path dependencies that get published must all be in
+		// the workspace, so that we can publish them too.
+		for package in &metadata.packages {
+			for dep in &package.dependencies {
+				if dep.path.is_some() && dep.kind.is_none() && !members.contains(&*dep.name) {
 					return Err(anyhow!(
-						"{crate_name} contains workspace dependency {dep} which cannot be found"
+						"{} contains path dependency {} which is not in the workspace",
+						package.name,
+						dep.name
 					))
 				}
 			}
 		}
 
+		let details: HashMap<String, CrateDetails> = metadata
+			.packages
+			.iter()
+			.map(|package| {
+				let crate_details = CrateDetails::load(package, &members, &metadata.workspace_root);
+				(package.name.clone(), crate_details)
+			})
+			.collect();
+
+		// Anything else that looks like a crate is probably a test fixture, or belongs to some
+		// other workspace, but mention it in case it was meant to be published too.
+		let member_tomls: HashSet<&Path> =
+			metadata.packages.iter().map(|p| &*p.manifest_path).collect();
+		let root_toml = metadata.workspace_root.join("Cargo.toml");
+		let stray_tomls: Vec<PathBuf> = crate_cargo_tomls(&metadata.workspace_root)
+			.into_iter()
+			.filter(|path| *path != root_toml && !member_tomls.contains(&**path))
+			.collect();
+		if !stray_tomls.is_empty() {
+			let paths: Vec<String> = stray_tomls.iter().map(|p| format!("  {p:?}")).collect();
+			log::warn!(
+				"Ignoring these Cargo.toml files, which aren't part of the workspace:\n{}",
+				paths.join("\n")
+			);
+		}
+
 		// Build a reverse dependency map, since it's useful to know which crates
 		// depend on a given crate in our workspace.
 		let mut dependees: HashMap<String, Dependees> =
@@ -94,7 +177,15 @@ impl Crates {
 			}
 		}
 
-		Ok(Crates { root, details, dependees })
+		let workspace_toml_path = root_toml;
+		let target_dir = metadata.target_directory;
+		Ok(Crates { root, registry, details, dependees, workspace_toml_path, target_dir })
+	}
+
+	/// Where we keep anything that has to outlive a run, like the publish journal. This is in
+	/// cargo's target directory, so that it's never packaged up along with a crate.
+	pub fn state_dir(&self) -> PathBuf {
+		self.target_dir.join("subpub")
 	}
 
 	/// Update the lockfile for the crates given and any of their dependencies if they've changed.
@@ -113,25 +204,343 @@ impl Crates {
 		external::cargo::update_lockfile_for_crates(&self.root, crates)
 	}
 
-	/// Remove any dev-dependency sections in the TOML file and publish.
-	pub fn strip_dev_deps_and_publish(&self, name: &str) -> anyhow::Result<()> {
+	/// Split the crates given, which are in the order that they'd need publishing, into waves.
+	/// No crate depends on any other crate in the same wave, so each wave can be published all
+	/// at once, once every wave before it has been.
+	pub fn group_into_waves(&self, names: &[String]) -> Vec<Vec<String>> {
+		fn wave_of(
+			all: &Crates,
+			name: &str,
+			in_set: &HashSet<&str>,
+			waves: &mut HashMap<String, usize>,
+		) -> usize {
+			if let Some(wave) = waves.get(name) {
+				return *wave
+			}
+			let details = all.details.get(name).expect("crates to publish exist; qed");
+			let wave = details
+				.deps
+				.iter()
+				.chain(&details.build_deps)
+				.filter(|dep| in_set.contains(dep.as_str()))
+				.map(|dep| wave_of(all, dep, in_set, waves) + 1)
+				.max()
+				.unwrap_or(0);
+			waves.insert(name.to_owned(), wave);
+			wave
+		}
+
+		let in_set: HashSet<&str> = names.iter().map(|name| &**name).collect();
+		let mut waves_by_name = HashMap::new();
+		let mut waves: Vec<Vec<String>> = vec![];
+		for name in names {
+			let wave = wave_of(self, name, &in_set, &mut waves_by_name);
+			if waves.len() <= wave {
+				waves.resize(wave + 1, vec![]);
+			}
+			waves[wave].push(name.clone());
+		}
+		waves
+	}
+
+	/// Remove any dev-dependency sections from the TOML files of the crates given and publish
+	/// them, publishing up to `publish_jobs` crates at once and then checking up to `jobs` at
+	/// once to see whether they've shown up in the registry. None of the crates may depend on
+	/// each other (see [`Crates::group_into_waves()`]). When publishing from the workspace, the
+	/// TOML files are restored from the backups given once published.
+	///
+	/// Each step is recorded in the journal given, and any steps that the journal says are
+	/// already done are skipped. One crate failing doesn't stop the others from being published,
+	/// and the result for each crate is handed back in the order given.
+	pub fn publish_wave(
+		&self,
+		names: &[String],
+		journal: &Mutex<Journal>,
+		from: &PublishFrom,
+		wait: &WaitOptions,
+		publish_jobs: usize,
+		jobs: usize,
+	) -> Vec<anyhow::Result<()>> {
+		let mut results: Vec<anyhow::Result<()>> = match from {
+			PublishFrom::Workspace(backups) => {
+				// Cargo reads every manifest in the workspace whenever it runs, so strip them
+				// all before publishing any, and only restore them once all are published, so
+				// that none of them change while cargo is reading them.
+				names.iter().map(|name| self.strip_dev_deps(name, journal, backups)).collect()
+			},
+			PublishFrom::Staging(_) => names.iter().map(|_| Ok(())).collect(),
+		};
+
+		self.for_each_ok_concurrently(names, &mut results, publish_jobs, |name| {
+			self.publish(name, journal, from)
+		});
+
+		if let PublishFrom::Workspace(backups) = from {
+			for (name, res) in names.iter().zip(&mut results) {
+				let restored = backups.restore(name);
+				if let (Ok(()), Err(e)) = (&res, restored) {
+					*res = Err(e);
+				}
+			}
+		}
+
+		// Don't return until the crates have finished being published; they won't be
+		// immediately visible in the registry, so wait until they show up.
+		self.for_each_ok_concurrently(names, &mut results, jobs, |name| {
+			self.wait_until_published(name, journal, wait)
+		});
+
+		results
+	}
+
+	/// Run `f` on up to `jobs` of the crates given at once, skipping any whose result is already
+	/// an error, and replacing the results of the rest with what `f` hands back.
+	fn for_each_ok_concurrently<F>(
+		&self,
+		names: &[String],
+		results: &mut [anyhow::Result<()>],
+		jobs: usize,
+		f: F,
+	) where
+		F: Fn(&str) -> anyhow::Result<()> + Sync,
+	{
+		let todo: Vec<usize> = (0..names.len()).filter(|&idx| results[idx].is_ok()).collect();
+		let done = parallel::map_concurrently(&todo, jobs, |&idx| f(&names[idx]));
+		for (idx, res) in todo.into_iter().zip(done) {
+			results[idx] = res;
+		}
+	}
+
+	/// Strip the dev dependencies from a crate in the workspace, unless it's already published.
+	fn strip_dev_deps(
+		&self,
+		name: &str,
+		journal: &Mutex<Journal>,
+		backups: &ManifestBackups,
+	) -> anyhow::Result<()> {
+		let details = self.details_for(name)?;
+		if journal_state(journal, name)? < CrateState::Published {
+			// The manifest may have been restored since we last stripped it, so always strip it
+			// again here. This does nothing if it's still stripped.
+			details.strip_dev_deps(backups)?;
+			set_journal_state(journal, name, CrateState::Stripped)?;
+		}
+		Ok(())
+	}
+
+	/// Publish a crate, unless it's already published. When publishing from the workspace, the
+	/// crate should already have had its dev dependencies stripped.
+	fn publish(
+		&self,
+		name: &str,
+		journal: &Mutex<Journal>,
+		from: &PublishFrom,
+	) -> anyhow::Result<()> {
+		let details = self.details_for(name)?;
+		if journal_state(journal, name)? >= CrateState::Published {
+			return Ok(())
+		}
+
+		match from {
+			PublishFrom::Workspace(_) => details.publish(&*self.registry)?,
+			PublishFrom::Staging(staging) => {
+				// Staged copies don't outlive a run, so always stage the crate afresh.
+				staging.check_committed(details.dir(), name)?;
+				let staged = details.stage(staging)?;
+				set_journal_state(journal, name, CrateState::Stripped)?;
+				// Everything but the manifest was just checked to match the commit, and the
+				// staged manifest has always been rewritten, so cargo has to allow for that.
+				self.registry.publish_crate(&staged, name, &details.version, true)?;
+			},
+		}
+		journal.lock().expect("no panics while locked; qed").set_uploaded(name)
+	}
+
+	/// Check that none of the crates given have uncommitted changes, before publishing any of
+	/// them from the staging area given. Every crate is checked, so that all of the problems can
+	/// be reported at once.
+	pub fn check_committed(&self, names: &[String], staging: &StagingArea) -> anyhow::Result<()> {
+		let mut problems = vec![];
+		for name in names {
+			let details = self.details_for(name)?;
+			if let Err(e) = staging.check_committed(details.dir(), name) {
+				problems.push(format!("{e:#}"));
+			}
+		}
+		if !problems.is_empty() {
+			anyhow::bail!(
+				"Crates can only be published from a staging area once their changes are committed:\n\n{}",
+				problems.join("\n\n")
+			);
+		}
+		Ok(())
+	}
+
+	/// Wait for a published crate to show up in the registry, unless we've already seen it.
+	fn wait_until_published(
+		&self,
+		name: &str,
+		journal: &Mutex<Journal>,
+		wait: &WaitOptions,
+	) -> anyhow::Result<()> {
+		let details = self.details_for(name)?;
+		if journal_state(journal, name)? < CrateState::Visible {
+			self.wait_until_visible(name, &details.version, wait)?;
+			set_journal_state(journal, name, CrateState::Visible)?;
+		}
+		Ok(())
+	}
+
+	fn details_for(&self, name: &str) -> anyhow::Result<&CrateDetails> {
+		match self.details.get(name) {
+			Some(details) => Ok(details),
+			None => anyhow::bail!("Crate '{name}' not found"),
+		}
+	}
+
+	/// Poll the registry until the given version of a crate shows up in it, giving up with an
+	/// error once the timeout is reached.
+	fn wait_until_visible(
+		&self,
+		name: &str,
+		version: &Version,
+		wait: &WaitOptions,
+	) -> anyhow::Result<()> {
+		let started = Instant::now();
+		loop {
+			let is_visible = match wait.wait_for {
+				WaitFor::Index => self.registry.does_crate_exist(name, version)?,
+				WaitFor::Api => self.registry.does_crate_exist_in_api(name, version)?,
+			};
+			if is_visible {
+				return Ok(())
+			}
+
+			let waited = started.elapsed();
+			if waited >= wait.timeout {
+				let place = match wait.wait_for {
+					WaitFor::Index => "index",
+					WaitFor::Api => "API",
+				};
+				anyhow::bail!(
+					"{name} {version} was published, but had not appeared in the registry {place} after waiting {}s",
+					waited.as_secs()
+				);
+			}
+			std::thread::sleep(wait.interval.min(wait.timeout - waited))
+		}
+	}
+
+	/// Check that the crate given would package up and build if it were published, without
+	/// publishing it. It's staged first, just like when publishing from a staging area. Any of
+	/// its dependencies in `unpublished` are taken from the workspace rather than the registry,
+	/// since they won't have been published yet.
+	pub fn dry_run_publish(
+		&self,
+		name: &str,
+		unpublished: &HashSet<String>,
+		staging: &StagingArea,
+		registry_name: Option<&str>,
+	) -> anyhow::Result<()> {
 		let details = match self.details.get(name) {
 			Some(details) => details,
 			None => anyhow::bail!("Crate '{name}' not found"),
 		};
 
-		details.strip_dev_deps()?;
-		details.publish()?;
+		// Anything that cargo can't get from the registry by itself is patched in, but crates
+		// in the workspace that are yet to be published take precedence over those.
+		let mut patches: BTreeMap<String, PathBuf> =
+			self.registry.dependency_patches()?.into_iter().collect();
+		for dep in details.deps.iter().chain(&details.build_deps) {
+			if !unpublished.contains(dep) {
+				continue
+			}
+			let dir = match self.details.get(dep) {
+				Some(dep_details) => dep_details.dir(),
+				None => anyhow::bail!("Crate '{dep}' not found"),
+			};
+			let dir = dir
+				.canonicalize()
+				.with_context(|| format!("Cannot find the crate directory {dir:?}"))?;
+			patches.insert(dep.clone(), dir);
+		}
+		let patches: Vec<(String, PathBuf)> = patches.into_iter().collect();
+
+		let staged = details.stage(staging)?;
+		external::cargo::verify_crate(&staged, name, registry_name, &patches, &staging.target_dir())
+	}
+
+	/// Check whether we're allowed to publish each of the crates given, as the given user if one
+	/// is given or else as whoever the configured token belongs to. Up to `jobs` crates are
+	/// checked at once. Hands back each crate that we can't publish, along with why.
+	pub fn publish_problems(
+		&self,
+		names: &[String],
+		publisher: Option<&str>,
+		jobs: usize,
+	) -> anyhow::Result<Vec<(String, String)>> {
+		let registry = &*self.registry;
+		let problems = parallel::map_concurrently(names, jobs, |name| {
+			registry.check_can_publish(name, publisher)
+		});
 
-		// Don't return until the crate has finished being published; it won't
-		// be immediately visible on crates.io, so wait until it shows up.
-		while !external::crates_io::does_crate_exist(name, &details.version)? {
-			std::thread::sleep(std::time::Duration::from_millis(2500))
+		let mut found = vec![];
+		for (name, problem) in names.iter().zip(problems) {
+			if let Some(problem) = problem? {
+				found.push((name.clone(), problem));
+			}
 		}
+		Ok(found)
+	}
+
+	/// Yank the given version of a crate from the registry.
+	pub fn yank_crate(&self, name: &str, version: &Version) -> anyhow::Result<()> {
+		self.registry.yank_crate(name, version)
+	}
+
+	/// Before resuming from a journal, check that it still matches the workspace, and bring it up
+	/// to date with the registry; a crate may have been published without us getting the chance
+	/// to record it.
+	pub fn reconcile_journal(&self, journal: &mut Journal) -> anyhow::Result<()> {
+		let entries = journal.entries().to_vec();
+		for entry in entries {
+			let name = &entry.name;
+			let details = match self.details.get(name) {
+				Some(details) => details,
+				None => anyhow::bail!(
+					"Crate '{name}' is in the publish journal but not in the workspace"
+				),
+			};
+			if details.version != entry.version {
+				anyhow::bail!(
+					"The publish journal expects {name} {}, but the workspace has {}",
+					entry.version,
+					details.version
+				);
+			}
 
+			let is_visible = self.registry.does_crate_exist(name, &details.version)?;
+			if is_visible && entry.state < CrateState::Visible {
+				log::info!("{name}: already in the registry; marking it as published");
+				journal.set_state(name, CrateState::Visible)?;
+			} else if !is_visible && entry.state == CrateState::Visible {
+				anyhow::bail!(
+					"The publish journal says that {name} {} was published, but it cannot be found in the registry",
+					entry.version
+				);
+			}
+		}
 		Ok(())
 	}
 
+	/// The current version of the crate given.
+	pub fn crate_version(&self, name: &str) -> anyhow::Result<&Version> {
+		match self.details.get(name) {
+			Some(details) => Ok(&details.version),
+			None => anyhow::bail!("Crate '{name}' not found"),
+		}
+	}
+
 	/// Does a crate need a version bump in order to publish?
 	pub fn does_crate_version_need_bumping_to_publish(
 		&mut self,
@@ -142,48 +551,156 @@ impl Crates {
 			None => anyhow::bail!("Crate '{name}' not found"),
 		};
 
-		details.needs_version_bump_to_publish()
+		details.needs_version_bump_to_publish(&*self.registry)
+	}
+
+	/// Which versions of the crate given have been yanked from the registry?
+	pub fn yanked_versions(&self, name: &str) -> anyhow::Result<Vec<Version>> {
+		let details = match self.details.get(name) {
+			Some(details) => details,
+			None => anyhow::bail!("Crate '{name}' not found"),
+		};
+
+		let known_versions = details.known_versions.get(&*self.registry)?;
+		let mut yanked: Vec<Version> = known_versions.yanked().cloned().collect();
+		yanked.sort();
+		Ok(yanked)
 	}
 
 	/// Bump the version of the crate given, and update it in all dependant crates as needed.
-	/// Return the old version and the new version.
+	/// Return the name, old version and new version of every crate bumped, which includes any
+	/// others that inherit their version from the workspace along with it. The new version is
+	/// newer than any that those crates have in the registry given (crates.io if `None`).
 	pub fn bump_crate_version_for_breaking_change(
 		&mut self,
 		name: &str,
-	) -> anyhow::Result<(Version, Version)> {
-		let details = match self.details.get_mut(name) {
+		registry_name: Option<&str>,
+	) -> anyhow::Result<Vec<(String, Version, Version)>> {
+		let details = match self.details.get(name) {
 			Some(details) => details,
 			None => anyhow::bail!("Crate '{name}' not found"),
 		};
-
 		let old_version = details.version.clone();
+
+		// If the version came from the workspace, then every other crate that inherits it is
+		// bumped too, so find those before changing anything.
+		let sharing_version = self.crates_sharing_version(name)?;
+
 		// Check if the `Cargo.toml` contained an outdate version, if so
-		// use the latest available on crates.io.
+		// use the latest available in the registry. Every crate sharing the version ends up on
+		// the new one, so it has to be newer than what any of them have published.
+		// Yanked versions count too, since their version numbers can't be reused.
 		let new_version = {
-			let mut latest_version = &old_version;
-			let known_versions = details.known_versions.get()?;
-			for version in &*known_versions {
-				if version > latest_version {
-					latest_version = version;
+			let mut latest_version = old_version.clone();
+			let mut outdated_by = None;
+			let publishable_sharing = sharing_version
+				.iter()
+				.filter(|other| self.details[*other].can_publish_to(registry_name));
+			for crate_name in std::iter::once(name).chain(publishable_sharing.map(|s| &**s)) {
+				let known_versions =
+					self.details[crate_name].known_versions.get(&*self.registry)?;
+				if let Some(latest) = known_versions.latest() {
+					if latest.version > latest_version {
+						latest_version = latest.version.clone();
+						outdated_by = Some((crate_name, latest.yanked));
+					}
 				}
 			}
-
-			if latest_version != &old_version {
-				println!("Crate '{name}' version {old_version} does not match crates.io version {latest_version}");
+			if let Some((crate_name, yanked)) = outdated_by {
+				let yanked = if yanked { " (yanked)" } else { "" };
+				println!(
+					"Crate '{crate_name}' version {old_version} does not match registry version {latest_version}{yanked}"
+				);
 			}
 
-			bump_for_breaking_change(latest_version.clone())
+			bump_for_breaking_change(latest_version)
 		};
 
 		// Bump the crate version:
+		let details = self.details.get_mut(name).expect("crate was found above; qed");
 		details.write_own_version(new_version.clone())?;
 
-		// Find any crate which depends on this crate and bump the version there too.
-		for details in self.details.values() {
-			details.write_dependency_version(name, &new_version)?;
+		let mut bumped = vec![(name.to_owned(), old_version, new_version.clone())];
+		for other in sharing_version {
+			println!(
+				"Crate '{other}' inherits its version from the workspace, like '{name}', so it is now {new_version} too"
+			);
+			let details =
+				self.details.get_mut(&other).expect("crates sharing a version exist; qed");
+			let old_version = std::mem::replace(&mut details.version, new_version.clone());
+			bumped.push((other, old_version, new_version.clone()));
+		}
+
+		// Find any crate which depends on the bumped crates and bump the version there too,
+		// including in the dependencies that crates can inherit from the workspace.
+		for (bumped, _, _) in &bumped {
+			for details in self.details.values() {
+				details.write_dependency_version(bumped, &new_version)?;
+			}
+			crate_details::write_workspace_dependency_version(
+				&self.workspace_toml_path,
+				bumped,
+				&new_version,
+			)?;
 		}
 
-		Ok((old_version, new_version))
+		Ok(bumped)
+	}
+
+	/// Check that bumping the versions of the crates given won't also bump a crate that isn't
+	/// being published, which would happen if they inherit their version from the workspace.
+	/// Crates that can't be published to the registry given (crates.io if `None`) at all are
+	/// fine to bump, since their version doesn't matter. Nothing is changed, so this can be done
+	/// before bumping anything.
+	pub fn check_version_bumps<S: AsRef<str>>(
+		&self,
+		bump_these: &[S],
+		publish_these: &[String],
+		registry_name: Option<&str>,
+	) -> anyhow::Result<()> {
+		let mut problems = vec![];
+		for name in bump_these {
+			let name = name.as_ref();
+			let not_published: Vec<String> = self
+				.crates_sharing_version(name)?
+				.into_iter()
+				.filter(|other| {
+					self.details[other].can_publish_to(registry_name) &&
+						!publish_these.contains(other)
+				})
+				.collect();
+			if !not_published.is_empty() {
+				problems.push(format!("  {name} would also bump {}", not_published.join(", ")));
+			}
+		}
+		if !problems.is_empty() {
+			anyhow::bail!(
+				"These crates inherit their version from the workspace, so bumping them would also bump crates that aren't being published (add those with -c):\n{}",
+				problems.join("\n")
+			);
+		}
+		Ok(())
+	}
+
+	/// The other crates whose version would change along with the crate given, because they
+	/// all inherit it from the workspace. Empty if the crate has a version of its own.
+	fn crates_sharing_version(&self, name: &str) -> anyhow::Result<Vec<String>> {
+		let details = match self.details.get(name) {
+			Some(details) => details,
+			None => anyhow::bail!("Crate '{name}' not found"),
+		};
+		if !details.inherits_version()? {
+			return Ok(vec![])
+		}
+
+		let mut sharing = vec![];
+		for details in self.details.values() {
+			if details.name != name && details.inherits_version()? {
+				sharing.push(details.name.clone());
+			}
+		}
+		sharing.sort();
+		Ok(sharing)
 	}
 
 	/// return a list of the crates that will need publishing in order to ensure that the
@@ -191,7 +708,18 @@ impl Crates {
 	///
 	/// **Note:** it may be that one or more of the crate names provided are already
 	/// published in their current state, in which case they won't be returned in the result.
-	pub fn what_needs_publishing(&self, crates: Vec<String>) -> anyhow::Result<Vec<String>> {
+	///
+	/// Crates which can't be published to `registry_name` (crates.io if `None`), because of their
+	/// `publish` setting, are never returned, and it's an error for anything that is returned to
+	/// depend on one of them.
+	///
+	/// Up to `jobs` crates will be compared against the registry at once.
+	pub fn what_needs_publishing(
+		&self,
+		crates: Vec<String>,
+		registry_name: Option<&str>,
+		jobs: usize,
+	) -> anyhow::Result<Vec<String>> {
 		struct Details<'a> {
 			dependees: HashSet<String>,
 			details: &'a CrateDetails,
@@ -200,6 +728,31 @@ impl Crates {
 		}
 		let mut tree: HashMap<String, Details> = HashMap::new();
 
+		// The crates asked for by name have to be publishable themselves, or there'd be no
+		// point; say why rather than quietly leaving them out.
+		let mut unpublishable = vec![];
+		for name in &crates {
+			let details = match self.details.get(name) {
+				Some(details) => details,
+				None => continue,
+			};
+			if details.can_publish_to(registry_name) {
+				continue
+			}
+			let reason = match details.publish.as_deref() {
+				Some([]) | None => "has `publish = false`".to_owned(),
+				Some(registries) => format!("can only be published to {}", registries.join(", ")),
+			};
+			unpublishable.push(format!("  {name} {reason}"));
+		}
+		if !unpublishable.is_empty() {
+			anyhow::bail!(
+				"Some of the crates given cannot be published to {}:\n{}",
+				registry_name.unwrap_or("crates.io"),
+				unpublishable.join("\n")
+			);
+		}
+
 		// Step 1: make a note of the crates we care about based on the names
 		// provided, which are the ones we ultimately want to be published
 		// in their current state.
@@ -239,6 +792,7 @@ impl Crates {
 		// build deps but ignore dev deps, since those are irrelevant for publishing.
 		// We need to wait until we have our entire sub-tree to do this, built above.
 
+		#[allow(clippy::iter_overeager_cloned)]
 		fn populate_deps<'a>(all: &'a Crates, tree: &mut HashMap<String, Details<'a>>) {
 			let crates_in_sub_tree: HashSet<String> = tree.keys().cloned().collect();
 			for (name, details) in tree.iter_mut() {
@@ -254,9 +808,23 @@ impl Crates {
 		}
 		populate_deps(self, &mut tree);
 
-		// Step 3: work out which of the crates in this graph need publishing. We work
-		// from the deepest dependencies up, and for each dependency we find that needs
-		// publishing, we mark all crates that depend on it as also needing publishing.
+		// Step 3: work out which of the crates in this graph need publishing. Each check
+		// involves talking to the registry, so we check all of the crates concurrently
+		// up front. Then we work from the deepest dependencies up, and for each dependency
+		// we find that needs publishing, we mark all crates that depend on it as also
+		// needing publishing. Crates that we aren't allowed to publish aren't checked.
+
+		let all_crates: Vec<&CrateDetails> = tree.values().map(|d| d.details).collect();
+		let registry = &*self.registry;
+		let crate_needs_publishing: HashMap<&str, bool> =
+			parallel::map_concurrently(&all_crates, jobs, |details| {
+				if !details.can_publish_to(registry_name) {
+					return Ok((&*details.name, false))
+				}
+				details.needs_publishing(registry).map(|needs| (&*details.name, needs))
+			})
+			.into_iter()
+			.collect::<anyhow::Result<_>>()?;
 
 		fn set_needs_publishing(tree: &mut HashMap<String, Details>, name: &str) {
 			let entry = tree.get_mut(name).expect("should exist");
@@ -281,7 +849,7 @@ impl Crates {
 
 			// If the crate itself needs publishing, mark it and anything
 			// depending on it as needing publishing.
-			if details.details.needs_publishing()? {
+			if crate_needs_publishing[&**name] {
 				set_needs_publishing(&mut tree, name);
 			}
 		}
@@ -290,42 +858,261 @@ impl Crates {
 		// in order to publish the crates originally provided. Return the list im the
 		// order that you'd need to publish them.
 
-		let crates_that_need_publishing = deepest_first
+		let crates_that_need_publishing: Vec<String> = deepest_first
 			.into_iter()
 			.map(|(name, _depth)| name)
-			.filter(|name| tree.get(name).unwrap().needs_publishing)
+			.filter(|name| {
+				let details = tree.get(name).unwrap();
+				details.needs_publishing && details.details.can_publish_to(registry_name)
+			})
 			.collect();
 
+		// Step 5: Make sure that none of the crates we'd publish depend on something that we
+		// can't publish, since they wouldn't build from the registry without it.
+
+		let mut problems = vec![];
+		for name in &crates_that_need_publishing {
+			let details = tree.get(name).unwrap().details;
+			let mut deps: Vec<&String> = details.deps.union(&details.build_deps).collect();
+			deps.sort();
+			for dep in deps {
+				let dep_details =
+					self.details.get(dep).expect("deps are all in the workspace; qed");
+				if !dep_details.can_publish_to(registry_name) {
+					problems.push(format!(
+						"  {name} depends on {dep}, which cannot be published to {}",
+						registry_name.unwrap_or("crates.io")
+					));
+				}
+			}
+		}
+		if !problems.is_empty() {
+			anyhow::bail!(
+				"Some crates depend on crates that cannot be published (see their `publish` setting):\n{}",
+				problems.join("\n")
+			);
+		}
+
 		Ok(crates_that_need_publishing)
 	}
 }
 
-/// find all of the crates, returning paths to their Cargo.toml files.
-fn crate_cargo_tomls(root: PathBuf) -> Vec<PathBuf> {
-	let root_toml = {
-		let mut p = root.clone();
-		p.push("Cargo.toml");
-		p
+/// Find the manifests of the crates that the `members` globs in the workspace manifest in the
+/// directory given point at, leaving out anything under a path in `exclude`. The root manifest
+/// is included too if it's a crate itself. Globs are expanded the same way that cargo does it.
+fn workspace_member_tomls(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+	let root_toml_path = root.join("Cargo.toml");
+	let root_toml = crate_details::read_toml(&root_toml_path)?;
+	let mut tomls = vec![];
+	if root_toml.get("package").is_some() {
+		tomls.push(root_toml_path.clone());
+	}
+
+	let workspace = match root_toml.get("workspace") {
+		Some(workspace) => workspace,
+		None => return Ok(tomls),
 	};
+	let strings = |key: &str| -> Vec<String> {
+		workspace
+			.get(key)
+			.and_then(|item| item.as_array())
+			.map(|array| array.iter().filter_map(|v| v.as_str()).map(String::from).collect())
+			.unwrap_or_default()
+	};
+	let exclude: Vec<PathBuf> = strings("exclude").iter().map(|path| root.join(path)).collect();
+
+	for pattern in strings("members") {
+		let full_pattern = root.join(&pattern);
+		let full_pattern = full_pattern.to_str().expect("workspace paths are valid UTF-8; qed");
+		let dirs = glob::glob(full_pattern)
+			.with_context(|| format!("Cannot expand the workspace member glob '{pattern}'"))?;
+		for dir in dirs {
+			let dir = dir
+				.with_context(|| format!("Cannot expand the workspace member glob '{pattern}'"))?;
+			let toml_path = dir.join("Cargo.toml");
+			let is_excluded = exclude.iter().any(|excluded| dir.starts_with(excluded));
+			if !is_excluded && toml_path.is_file() && !tomls.contains(&toml_path) {
+				tomls.push(toml_path);
+			}
+		}
+	}
+	Ok(tomls)
+}
+
+/// Find every Cargo.toml file under the root given, ignoring hidden directories and anything
+/// in "target" directories. Directories that can't be read are skipped.
+fn crate_cargo_tomls(root: &Path) -> Vec<PathBuf> {
+	let mut tomls = vec![];
+	let mut dirs = vec![root.to_path_buf()];
+	while let Some(dir) = dirs.pop() {
+		let entries = match std::fs::read_dir(&dir) {
+			Ok(entries) => entries,
+			Err(_) => continue,
+		};
+		for entry in entries.filter_map(|entry| entry.ok()) {
+			let file_type = match entry.file_type() {
+				Ok(file_type) => file_type,
+				Err(_) => continue,
+			};
+			let name = entry.file_name();
+			let name = name.to_str().unwrap_or_default();
+			if file_type.is_dir() && !name.starts_with('.') && name != "target" {
+				dirs.push(entry.path());
+			} else if file_type.is_file() && name == "Cargo.toml" {
+				tomls.push(entry.path());
+			}
+		}
+	}
+	tomls.sort();
+	tomls
+}
+
+fn journal_state(journal: &Mutex<Journal>, name: &str) -> anyhow::Result<CrateState> {
+	match journal.lock().expect("no panics while locked; qed").state(name) {
+		Some(state) => Ok(state),
+		None => anyhow::bail!("Crate '{name}' is not in the publish journal"),
+	}
+}
+
+fn set_journal_state(
+	journal: &Mutex<Journal>,
+	name: &str,
+	state: CrateState,
+) -> anyhow::Result<()> {
+	journal.lock().expect("no panics while locked; qed").set_state(name, state)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-	WalkDir::new(root)
-		.into_iter()
-		// Ignore hidden files and folders, and anything in "target" folders
-		.filter_entry(|entry| {
-			entry
-				.file_name()
-				.to_str()
-				.map(|s| !s.starts_with('.') && s != "target")
-				.unwrap_or(false)
-		})
-		// Ignore errors
-		.filter_map(|entry| entry.ok())
-		// Keep files
-		.filter(|entry| entry.file_type().is_file())
-		// Keep files named Cargo.toml
-		.filter(|entry| entry.file_name().to_str().map(|s| s == "Cargo.toml").unwrap_or(false))
-		// Filter the root Cargo.toml file
-		.filter(|entry| entry.path() != root_toml)
-		.map(|entry| entry.into_path())
-		.collect()
+	fn write(root: &Path, path: &str, contents: &str) {
+		let path = root.join(path);
+		std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+		std::fs::write(path, contents).unwrap();
+	}
+
+	fn package(name: &str) -> String {
+		format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n")
+	}
+
+	fn member_tomls(root: &Path) -> Vec<String> {
+		let mut tomls: Vec<String> = workspace_member_tomls(root)
+			.unwrap()
+			.into_iter()
+			.map(|path| path.strip_prefix(root).unwrap().to_str().unwrap().to_owned())
+			.collect();
+		tomls.sort();
+		tomls
+	}
+
+	#[test]
+	fn member_globs_are_expanded_like_cargo() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path();
+		write(
+			root,
+			"Cargo.toml",
+			"[workspace]\nmembers = [\"crates/*\", \"nested/**\", \"tools/[ab]\", \"bin\"]\n",
+		);
+		write(root, "crates/one/Cargo.toml", &package("one"));
+		write(root, "crates/two/Cargo.toml", &package("two"));
+		write(root, "crates/not-a-crate/README.md", "");
+		write(root, "nested/x/Cargo.toml", &package("x"));
+		write(root, "nested/x/y/Cargo.toml", &package("y"));
+		write(root, "tools/a/Cargo.toml", &package("a"));
+		write(root, "tools/c/Cargo.toml", &package("c"));
+		write(root, "bin/Cargo.toml", &package("bin"));
+
+		assert_eq!(
+			member_tomls(root),
+			vec![
+				"bin/Cargo.toml",
+				"crates/one/Cargo.toml",
+				"crates/two/Cargo.toml",
+				"nested/x/Cargo.toml",
+				"nested/x/y/Cargo.toml",
+				"tools/a/Cargo.toml",
+			]
+		);
+	}
+
+	#[test]
+	fn excluded_paths_are_not_members() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path();
+		write(
+			root,
+			"Cargo.toml",
+			&format!(
+				"{}[workspace]\nmembers = [\"crates/*\"]\nexclude = [\"crates/fixture\"]\n",
+				package("root")
+			),
+		);
+		write(root, "crates/one/Cargo.toml", &package("one"));
+		write(root, "crates/fixture/Cargo.toml", &package("fixture"));
+
+		assert_eq!(member_tomls(root), vec!["Cargo.toml", "crates/one/Cargo.toml"]);
+	}
+
+	#[test]
+	fn stray_manifests_skip_hidden_and_target_dirs() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path();
+		write(root, "Cargo.toml", "");
+		write(root, "crates/one/Cargo.toml", "");
+		write(root, "crates/one/tests/fixture/Cargo.toml", "");
+		write(root, "target/package/one/Cargo.toml", "");
+		write(root, ".git/Cargo.toml", "");
+
+		let tomls: Vec<PathBuf> = crate_cargo_tomls(root)
+			.into_iter()
+			.map(|path| path.strip_prefix(root).unwrap().to_path_buf())
+			.collect();
+		assert_eq!(
+			tomls,
+			vec![
+				PathBuf::from("Cargo.toml"),
+				PathBuf::from("crates/one/Cargo.toml"),
+				PathBuf::from("crates/one/tests/fixture/Cargo.toml"),
+			]
+		);
+	}
+
+	#[test]
+	fn crates_are_grouped_into_waves_after_their_dependencies() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path();
+		let with_deps = |name: &str, section: &str, deps: &[&str]| {
+			let deps: String =
+				deps.iter().map(|dep| format!("{dep} = {{ path = \"../{dep}\" }}\n")).collect();
+			format!("{}[{section}]\n{deps}", package(name))
+		};
+		write(root, "Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n");
+		write(root, "crates/a/Cargo.toml", &package("a"));
+		write(root, "crates/b/Cargo.toml", &with_deps("b", "dependencies", &["a"]));
+		write(root, "crates/c/Cargo.toml", &with_deps("c", "dependencies", &["a"]));
+		write(root, "crates/d/Cargo.toml", &with_deps("d", "build-dependencies", &["b", "c"]));
+		write(root, "crates/e/Cargo.toml", &with_deps("e", "dev-dependencies", &["d"]));
+		for name in ["a", "b", "c", "d", "e"] {
+			write(root, &format!("crates/{name}/src/lib.rs"), "");
+		}
+
+		let registry =
+			Arc::new(external::local_registry::LocalRegistry::new(root.join("registry")));
+		let crates = Crates::load_crates_in_workspace(root.to_path_buf(), registry).unwrap();
+		let names =
+			|names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+
+		// Dev dependencies are stripped before publishing, so they don't hold a crate back.
+		assert_eq!(
+			crates.group_into_waves(&names(&["a", "e", "b", "c", "d"])),
+			vec![names(&["a", "e"]), names(&["b", "c"]), names(&["d"])]
+		);
+		// Dependencies that aren't being published don't either.
+		assert_eq!(
+			crates.group_into_waves(&names(&["c", "d"])),
+			vec![names(&["c"]), names(&["d"])]
+		);
+	}
 }
@@ -0,0 +1,249 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{KnownVersions, Registry};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Wraps another registry, keeping a copy of what it hands back on disk so that we don't need to
+/// ask again next time. Published `.crate` files never change, so they are kept forever, but the
+/// list of versions for a crate is only trusted for a short while.
+///
+/// In offline mode there is no registry to fall back to, and so everything has to be answered
+/// from the cache (regardless of how old it is), and anything missing is an error.
+#[derive(Debug)]
+pub struct CachedRegistry {
+	/// The registry to ask for anything not cached, or `None` if we're offline.
+	inner: Option<Box<dyn Registry>>,
+	dir: PathBuf,
+	versions_ttl: Duration,
+}
+
+/// What we store on disk for each crate's list of versions.
+#[derive(Serialize, Deserialize)]
+struct CachedVersions {
+	/// Seconds since the unix epoch at which we fetched this list.
+	fetched_at: u64,
+	versions: KnownVersions,
+}
+
+impl CachedRegistry {
+	/// Cache everything that `inner` hands back in `dir`, trusting version lists for
+	/// `versions_ttl`.
+	pub fn new(inner: Box<dyn Registry>, dir: PathBuf, versions_ttl: Duration) -> Self {
+		CachedRegistry { inner: Some(inner), dir, versions_ttl }
+	}
+
+	/// Answer everything from the cache in `dir`, without ever touching the network.
+	pub fn offline(dir: PathBuf) -> Self {
+		CachedRegistry { inner: None, dir, versions_ttl: Duration::MAX }
+	}
+
+	/// The default place to keep cached registry data, if we can work one out.
+	pub fn default_dir() -> Option<PathBuf> {
+		if let Ok(dir) = std::env::var("XDG_CACHE_HOME") {
+			return Some(PathBuf::from(dir).join("subpub"))
+		}
+		std::env::var("HOME")
+			.ok()
+			.map(|home| PathBuf::from(home).join(".cache").join("subpub"))
+	}
+
+	fn crate_file(&self, name: &str, version: &semver::Version) -> PathBuf {
+		self.dir.join("crates").join(name).join(format!("{name}-{version}.crate"))
+	}
+
+	fn versions_file(&self, name: &str) -> PathBuf {
+		self.dir.join("versions").join(format!("{name}.json"))
+	}
+
+	/// Read the cached versions for a crate, if we have them and they are fresh enough.
+	fn read_versions(&self, name: &str) -> Option<KnownVersions> {
+		let contents = std::fs::read(self.versions_file(name)).ok()?;
+		let cached: CachedVersions = match serde_json::from_slice(&contents) {
+			Ok(cached) => cached,
+			Err(e) => {
+				log::debug!("{name}: ignoring unreadable cached versions: {e}");
+				return None
+			},
+		};
+
+		let age = now().saturating_sub(cached.fetched_at);
+		if age > self.versions_ttl.as_secs() {
+			return None
+		}
+		Some(cached.versions)
+	}
+
+	/// The registry to fall back to, or an error explaining what's missing if we're offline.
+	fn inner(&self, missing: impl FnOnce() -> String) -> anyhow::Result<&dyn Registry> {
+		match &self.inner {
+			Some(inner) => Ok(&**inner),
+			None => anyhow::bail!(
+				"We're offline, but {} is not in the cache at {:?}; run without --offline first to populate it",
+				missing(),
+				self.dir
+			),
+		}
+	}
+
+	/// Remove the cached list of versions for a crate, once we know that it's out of date.
+	fn forget_versions(&self, name: &str) -> anyhow::Result<()> {
+		let versions_file = self.versions_file(name);
+		if versions_file.exists() {
+			std::fs::remove_file(&versions_file)
+				.with_context(|| format!("Cannot remove stale cache file {versions_file:?}"))?;
+		}
+		Ok(())
+	}
+
+	fn write_versions(&self, name: &str, versions: &KnownVersions) -> anyhow::Result<()> {
+		let cached = CachedVersions { fetched_at: now(), versions: versions.clone() };
+		write_atomically(&self.versions_file(name), &serde_json::to_vec(&cached)?)
+	}
+}
+
+impl Registry for CachedRegistry {
+	fn does_crate_exist(&self, name: &str, version: &semver::Version) -> anyhow::Result<bool> {
+		// Versions never disappear once published, so if we already know about this one we
+		// can say so. Otherwise, it may have appeared since, so we have to go and look.
+		if let Some(versions) = self.read_versions(name) {
+			if versions.contains(version) {
+				return Ok(true)
+			}
+			if self.inner.is_none() {
+				return Ok(false)
+			}
+		}
+		self.inner(|| format!("the list of versions for {name}"))?
+			.does_crate_exist(name, version)
+	}
+
+	/// This is only used to wait for crates that we've just published, so it's never cached.
+	fn does_crate_exist_in_api(
+		&self,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<bool> {
+		self.inner(|| format!("{name} {version}"))?
+			.does_crate_exist_in_api(name, version)
+	}
+
+	fn try_download_crate(
+		&self,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<Option<Vec<u8>>> {
+		let path = self.crate_file(name, version);
+		if let Ok(bytes) = std::fs::read(&path) {
+			log::debug!("{name}: using cached crate at {path:?}");
+			return Ok(Some(bytes))
+		}
+
+		// If we know the version doesn't exist then there's nothing to download, and no need to
+		// complain that it's not in the cache.
+		if self.inner.is_none() && !self.does_crate_exist(name, version)? {
+			return Ok(None)
+		}
+
+		let bytes =
+			self.inner(|| format!("{name} {version}"))?.try_download_crate(name, version)?;
+		if let Some(bytes) = &bytes {
+			write_atomically(&path, bytes)?;
+		}
+		Ok(bytes)
+	}
+
+	fn get_known_crate_versions(&self, name: &str) -> anyhow::Result<KnownVersions> {
+		if let Some(versions) = self.read_versions(name) {
+			return Ok(versions)
+		}
+
+		let versions = self
+			.inner(|| format!("the list of versions for {name}"))?
+			.get_known_crate_versions(name)?;
+		self.write_versions(name, &versions)?;
+		Ok(versions)
+	}
+
+	fn publish_crate(
+		&self,
+		root: &Path,
+		name: &str,
+		version: &semver::Version,
+		allow_dirty: bool,
+	) -> anyhow::Result<()> {
+		match &self.inner {
+			Some(inner) => inner.publish_crate(root, name, version, allow_dirty)?,
+			None => anyhow::bail!("Cannot publish {name} {version} while offline"),
+		}
+
+		// Our list of versions for this crate is now out of date.
+		self.forget_versions(name)
+	}
+
+	fn dependency_patches(&self) -> anyhow::Result<Vec<(String, PathBuf)>> {
+		match &self.inner {
+			Some(inner) => inner.dependency_patches(),
+			None => Ok(vec![]),
+		}
+	}
+
+	fn yank_crate(&self, name: &str, version: &semver::Version) -> anyhow::Result<()> {
+		match &self.inner {
+			Some(inner) => inner.yank_crate(name, version)?,
+			None => anyhow::bail!("Cannot yank {name} {version} while offline"),
+		}
+
+		// Our list of versions for this crate no longer says which are yanked.
+		self.forget_versions(name)
+	}
+
+	fn check_can_publish(
+		&self,
+		name: &str,
+		publisher: Option<&str>,
+	) -> anyhow::Result<Option<String>> {
+		self.inner(|| format!("the owners of {name}"))?
+			.check_can_publish(name, publisher)
+	}
+}
+
+/// Write a file by writing to a temporary file alongside it and then renaming that into place,
+/// so that an interrupted run never leaves a half-written file in the cache.
+fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+	let dir = path.parent().expect("cache files always have a parent; qed");
+	std::fs::create_dir_all(dir)
+		.with_context(|| format!("Cannot create cache directory {dir:?}"))?;
+
+	let mut tmp_name = path.file_name().expect("cache files always have a name; qed").to_owned();
+	tmp_name.push(format!(".{}.tmp", std::process::id()));
+	let tmp_path = dir.join(tmp_name);
+
+	std::fs::write(&tmp_path, contents)
+		.with_context(|| format!("Cannot write cache file {tmp_path:?}"))?;
+	std::fs::rename(&tmp_path, path)
+		.with_context(|| format!("Cannot move cache file into place at {path:?}"))?;
+	Ok(())
+}
+
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -14,7 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with subpub.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::{path::Path, process::Command};
+use super::cargo_metadata::Metadata;
+use anyhow::Context;
+use std::{
+	io::{BufRead, BufReader, Read},
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+};
+
+/// How many lines of stderr from a failed command to include in the error.
+const STDERR_LINES_IN_ERROR: usize = 50;
 
 /// Update the lockfile for dependencies given and any of their subdependencies.
 pub fn update_lockfile_for_crates<I, S>(root: &Path, deps: I) -> anyhow::Result<()>
@@ -29,20 +38,201 @@ where
 		cmd.arg("-p").arg(dep.as_ref());
 	}
 
-	cmd.status()?;
-	Ok(())
+	run(&mut cmd)
 }
 
-/// Update the lockfile for dependencies given and any of their subdependencies.
-pub fn publish_crate(root: &Path, package: &str) -> anyhow::Result<()> {
+/// Ask cargo about the members of the workspace at `root`.
+pub fn workspace_metadata(root: &Path) -> anyhow::Result<Metadata> {
+	let mut cmd = Command::new("cargo");
+
+	cmd.current_dir(root)
+		.arg("metadata")
+		.arg("--format-version")
+		.arg("1")
+		.arg("--no-deps");
+	let stdout = run_with_output(&mut cmd)?;
+
+	serde_json::from_str(&stdout).context("Cannot parse the output of `cargo metadata`")
+}
+
+/// Package the given crate up into a `.crate` file without publishing it, returning the path to
+/// that file. This doesn't verify that the packaged crate builds, since its dependencies may not
+/// be available from crates.io. Cargo is run offline, and takes each of the `patches` given from
+/// the directory alongside it rather than from crates.io.
+pub fn package_crate(
+	root: &Path,
+	package: &str,
+	version: &semver::Version,
+	target_dir: &Path,
+	patches: &[(String, PathBuf)],
+	allow_dirty: bool,
+) -> anyhow::Result<PathBuf> {
 	let mut cmd = Command::new("cargo");
 
 	cmd.current_dir(root)
-		.arg("publish")
+		.arg("package")
+		.arg("-p")
+		.arg(package)
+		.arg("--no-verify")
+		.arg("--offline")
+		.arg("--target-dir")
+		.arg(target_dir);
+	for (name, path) in patches {
+		let path = toml_edit::Value::from(path.to_string_lossy().as_ref());
+		cmd.arg("--config").arg(format!("patch.crates-io.{name}.path={path}"));
+	}
+	if allow_dirty {
+		cmd.arg("--allow-dirty");
+	}
+	run(&mut cmd)?;
+
+	Ok(target_dir.join("package").join(format!("{package}-{version}.crate")))
+}
+
+/// List the files that would be included if the given package were packaged up, relative to the
+/// package root. This includes files that cargo generates itself, like `Cargo.toml.orig`.
+pub fn list_package_files(root: &Path, package: &str) -> anyhow::Result<Vec<PathBuf>> {
+	let mut cmd = Command::new("cargo");
+
+	cmd.current_dir(root)
+		.arg("package")
+		.arg("--list")
+		.arg("-p")
+		.arg(package)
+		.arg("--allow-dirty");
+	let stdout = run_with_output(&mut cmd)?;
+
+	Ok(stdout.lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect())
+}
+
+/// Package the given crate up and check that the packaged crate builds, without publishing it.
+/// Any `patches` given, as pairs of package name and directory, are used in place of those
+/// packages from the registry, so that a crate can be checked before its dependencies have been
+/// published. They patch the named registry if one is given, or else crates.io. Crates are only
+/// verified from a staging area, where the manifest has always been rewritten, so uncommitted
+/// changes are allowed; publishing checks for them separately.
+pub fn verify_crate(
+	root: &Path,
+	package: &str,
+	registry: Option<&str>,
+	patches: &[(String, PathBuf)],
+	target_dir: &Path,
+) -> anyhow::Result<()> {
+	let mut cmd = Command::new("cargo");
+
+	cmd.current_dir(root)
+		.arg("package")
 		.arg("-p")
 		.arg(package)
 		.arg("--allow-dirty")
-		.status()?;
+		.arg("--target-dir")
+		.arg(target_dir);
+
+	if let Some(registry) = registry {
+		cmd.arg("--registry").arg(registry);
+	}
+	let patch_table = registry.unwrap_or("crates-io");
+	for (name, path) in patches {
+		let path = toml_edit::Value::from(path.to_string_lossy().as_ref());
+		cmd.arg("--config").arg(format!("patch.{patch_table}.{name}.path={path}"));
+	}
+
+	run(&mut cmd)
+}
+
+/// Publish the given package, to the named registry if one is given or else to crates.io.
+pub fn publish_crate(
+	root: &Path,
+	package: &str,
+	registry: Option<&str>,
+	allow_dirty: bool,
+) -> anyhow::Result<()> {
+	let mut cmd = Command::new("cargo");
+
+	cmd.current_dir(root).arg("publish").arg("-p").arg(package);
+
+	if let Some(registry) = registry {
+		cmd.arg("--registry").arg(registry);
+	}
+	if allow_dirty {
+		cmd.arg("--allow-dirty");
+	}
+
+	run(&mut cmd)
+}
+
+/// Yank the given version of a package, from the named registry if one is given or else from
+/// crates.io.
+pub fn yank_crate(
+	package: &str,
+	version: &semver::Version,
+	registry: Option<&str>,
+) -> anyhow::Result<()> {
+	let mut cmd = Command::new("cargo");
+
+	cmd.arg("yank").arg("--version").arg(version.to_string()).arg(package);
+
+	if let Some(registry) = registry {
+		cmd.arg("--registry").arg(registry);
+	}
+
+	run(&mut cmd)
+}
+
+/// Run a command to completion, returning an error if it doesn't succeed. Stderr is shown as the
+/// command runs, but we also keep hold of it so that the tail end can be included in the error.
+fn run(cmd: &mut Command) -> anyhow::Result<()> {
+	run_inner(cmd, false).map(|_| ())
+}
+
+/// Like [`run`], but hand back whatever the command wrote to stdout rather than showing it.
+fn run_with_output(cmd: &mut Command) -> anyhow::Result<String> {
+	run_inner(cmd, true)
+}
+
+fn run_inner(cmd: &mut Command, capture_stdout: bool) -> anyhow::Result<String> {
+	let cmd_string = {
+		let args: Vec<_> = cmd.get_args().map(|arg| arg.to_string_lossy()).collect();
+		format!("{} {}", cmd.get_program().to_string_lossy(), args.join(" "))
+	};
+
+	if capture_stdout {
+		cmd.stdout(Stdio::piped());
+	}
+	let mut child = cmd
+		.stderr(Stdio::piped())
+		.spawn()
+		.with_context(|| format!("Cannot run `{cmd_string}`"))?;
+
+	// Read stdout on another thread so that neither pipe can fill up and block the command.
+	let stdout_reader = child.stdout.take().map(|mut stdout| {
+		std::thread::spawn(move || {
+			let mut output = String::new();
+			stdout.read_to_string(&mut output).map(|_| output)
+		})
+	});
+
+	let mut stderr_lines = vec![];
+	let stderr = child.stderr.take().expect("stderr is piped above; qed");
+	for line in BufReader::new(stderr).lines() {
+		let line = line.with_context(|| format!("Cannot read stderr of `{cmd_string}`"))?;
+		eprintln!("{line}");
+		stderr_lines.push(line);
+	}
+
+	let status = child.wait().with_context(|| format!("Cannot run `{cmd_string}`"))?;
+	if !status.success() {
+		let skip = stderr_lines.len().saturating_sub(STDERR_LINES_IN_ERROR);
+		let stderr = stderr_lines[skip..].join("\n");
+		anyhow::bail!("`{cmd_string}` failed ({status}):\n{stderr}");
+	}
 
-	Ok(())
+	let stdout = match stdout_reader {
+		Some(reader) => reader
+			.join()
+			.expect("reading stdout doesn't panic; qed")
+			.with_context(|| format!("Cannot read stdout of `{cmd_string}`"))?,
+		None => String::new(),
+	};
+	Ok(stdout)
 }
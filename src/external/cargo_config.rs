@@ -0,0 +1,138 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Look things up in cargo's configuration files, in roughly the same way that cargo does. See
+//! <https://doc.rust-lang.org/cargo/reference/config.html>.
+
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Find the index URL configured for the registry with the given name. This looks at the
+/// `CARGO_REGISTRIES_<NAME>_INDEX` environment variable, and then at `[registries.<name>]` in
+/// each `.cargo/config.toml` from `root` upwards, and finally in `$CARGO_HOME/config.toml`.
+pub fn registry_index_url(root: &Path, name: &str) -> anyhow::Result<String> {
+	if let Ok(index) = std::env::var(registry_env_var(name, "INDEX")) {
+		return Ok(index)
+	}
+
+	for path in config_paths(root) {
+		let index = read_registry_value(&path, "registries", name, "index")?;
+		if let Some(index) = index {
+			return Ok(index)
+		}
+	}
+
+	anyhow::bail!("Cannot find an index URL for the registry '{name}' in any cargo config file")
+}
+
+/// Find the token to use for the registry with the given name, or for crates.io if no name
+/// is given. As with cargo, environment variables take precedence over `credentials.toml`.
+pub fn registry_token(name: Option<&str>) -> anyhow::Result<Option<String>> {
+	let env_var = match name {
+		Some(name) => registry_env_var(name, "TOKEN"),
+		None => "CARGO_REGISTRY_TOKEN".to_string(),
+	};
+	if let Ok(token) = std::env::var(env_var) {
+		return Ok(Some(token))
+	}
+
+	let cargo_home = match cargo_home() {
+		Some(cargo_home) => cargo_home,
+		None => return Ok(None),
+	};
+	for file_name in ["credentials.toml", "credentials"] {
+		let path = cargo_home.join(file_name);
+		let token = match name {
+			Some(name) => read_registry_value(&path, "registries", name, "token")?,
+			None => read_value(&path, "registry", "token")?,
+		};
+		if token.is_some() {
+			return Ok(token)
+		}
+	}
+
+	Ok(None)
+}
+
+/// The environment variable that cargo looks at to configure some property of a registry.
+fn registry_env_var(name: &str, property: &str) -> String {
+	let name = name.to_uppercase().replace('-', "_");
+	format!("CARGO_REGISTRIES_{name}_{property}")
+}
+
+/// All of the cargo config files that apply to the given directory, in order of precedence.
+fn config_paths(root: &Path) -> Vec<PathBuf> {
+	let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+	let mut dirs: Vec<PathBuf> = root.ancestors().map(|dir| dir.join(".cargo")).collect();
+	dirs.extend(cargo_home());
+
+	dirs.into_iter()
+		.flat_map(|dir| [dir.join("config.toml"), dir.join("config")])
+		.filter(|path| path.is_file())
+		.collect()
+}
+
+/// Where does cargo keep its global configuration?
+fn cargo_home() -> Option<PathBuf> {
+	if let Ok(home) = std::env::var("CARGO_HOME") {
+		return Some(PathBuf::from(home))
+	}
+	std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(".cargo"))
+}
+
+/// Read a string value from `[table.name]` in a TOML file, if the file exists.
+fn read_registry_value(
+	path: &Path,
+	table: &str,
+	name: &str,
+	key: &str,
+) -> anyhow::Result<Option<String>> {
+	let toml = match read_config(path)? {
+		Some(toml) => toml,
+		None => return Ok(None),
+	};
+	Ok(toml
+		.get(table)
+		.and_then(|t| t.get(name))
+		.and_then(|t| t.get(key))
+		.and_then(|v| v.as_str())
+		.map(|v| v.to_owned()))
+}
+
+/// Read a string value from `[table]` in a TOML file, if the file exists.
+fn read_value(path: &Path, table: &str, key: &str) -> anyhow::Result<Option<String>> {
+	let toml = match read_config(path)? {
+		Some(toml) => toml,
+		None => return Ok(None),
+	};
+	Ok(toml
+		.get(table)
+		.and_then(|t| t.get(key))
+		.and_then(|v| v.as_str())
+		.map(|v| v.to_owned()))
+}
+
+fn read_config(path: &Path) -> anyhow::Result<Option<toml_edit::Document>> {
+	if !path.is_file() {
+		return Ok(None)
+	}
+	let contents = std::fs::read_to_string(path)
+		.with_context(|| format!("Cannot read the cargo config at {path:?}"))?;
+	let toml = contents
+		.parse::<toml_edit::Document>()
+		.with_context(|| format!("Cannot parse the cargo config at {path:?}"))?;
+	Ok(Some(toml))
+}
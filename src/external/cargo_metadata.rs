@@ -0,0 +1,59 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The parts of the output of `cargo metadata` that we care about. See
+//! <https://doc.rust-lang.org/cargo/commands/cargo-metadata.html#json-format>.
+
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+	/// Without `--no-deps`, this would include every dependency too, but we only ever ask for
+	/// the workspace members.
+	pub packages: Vec<Package>,
+	pub workspace_root: PathBuf,
+	pub target_directory: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Package {
+	pub name: String,
+	/// The version, with any inheritance from the workspace already resolved.
+	pub version: semver::Version,
+	pub manifest_path: PathBuf,
+	pub dependencies: Vec<Dependency>,
+	/// The registries that this may be published to, or `None` if it can be published anywhere.
+	/// `publish = false` shows up as an empty list.
+	pub publish: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Dependency {
+	/// The name of the package depended on, even if it's renamed in the manifest.
+	pub name: String,
+	/// `None` for normal dependencies.
+	pub kind: Option<DependencyKind>,
+	/// Where the dependency lives, if it's a path dependency.
+	pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyKind {
+	Dev,
+	Build,
+}
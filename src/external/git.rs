@@ -0,0 +1,97 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::Context;
+use std::{
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+/// The root of the git repository that `path` is in, or `None` if it isn't in one (or git
+/// isn't installed).
+pub fn repo_root(path: &Path) -> anyhow::Result<Option<PathBuf>> {
+	let mut cmd = Command::new("git");
+	cmd.arg("-C").arg(path).arg("rev-parse").arg("--show-toplevel");
+
+	let output = match cmd.output() {
+		Ok(output) => output,
+		Err(e) => {
+			log::debug!("Cannot run git, so assuming that {path:?} is not in a repository: {e}");
+			return Ok(None)
+		},
+	};
+	if !output.status.success() {
+		return Ok(None)
+	}
+
+	let root = String::from_utf8(output.stdout).context("git gave back a non UTF-8 path")?;
+	Ok(Some(PathBuf::from(root.trim_end())))
+}
+
+/// Add a worktree of the repository at `repo` in `dir`, at the current commit. Nothing is
+/// checked out into it, but the index matches the commit, so that any files copied into the
+/// worktree are compared against the commit as they would be in the repository itself.
+pub fn add_empty_worktree(repo: &Path, dir: &Path) -> anyhow::Result<()> {
+	let mut cmd = Command::new("git");
+	cmd.arg("-C")
+		.arg(repo)
+		.arg("worktree")
+		.arg("add")
+		.arg("--detach")
+		.arg("--no-checkout")
+		.arg(dir)
+		.arg("HEAD");
+	run(&mut cmd)?;
+
+	let mut cmd = Command::new("git");
+	cmd.arg("-C").arg(dir).arg("read-tree").arg("HEAD");
+	run(&mut cmd)?;
+	Ok(())
+}
+
+/// Remove a worktree added with [`add_empty_worktree`], along with anything in it.
+pub fn remove_worktree(repo: &Path, dir: &Path) -> anyhow::Result<()> {
+	let mut cmd = Command::new("git");
+	cmd.arg("-C").arg(repo).arg("worktree").arg("remove").arg("--force").arg(dir);
+	run(&mut cmd)?;
+	Ok(())
+}
+
+/// The files under `path` that differ from the current commit of the repository at `repo`,
+/// including untracked files, as described by `git status --porcelain`.
+pub fn uncommitted_changes(repo: &Path, path: &Path) -> anyhow::Result<Vec<String>> {
+	let mut cmd = Command::new("git");
+	cmd.arg("-C").arg(repo).arg("status").arg("--porcelain").arg("--").arg(path);
+	let stdout = run(&mut cmd)?;
+	Ok(stdout.lines().map(String::from).collect())
+}
+
+/// Run a git command to completion, returning what it printed to stdout, or an error including
+/// what it printed to stderr if it fails.
+fn run(cmd: &mut Command) -> anyhow::Result<String> {
+	let cmd_string = {
+		let args: Vec<_> = cmd.get_args().map(|arg| arg.to_string_lossy()).collect();
+		format!("{} {}", cmd.get_program().to_string_lossy(), args.join(" "))
+	};
+
+	let output = cmd.output().with_context(|| format!("Cannot run `{cmd_string}`"))?;
+	if !output.status.success() {
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		anyhow::bail!("`{cmd_string}` failed ({}):\n{}", output.status, stderr.trim_end());
+	}
+	String::from_utf8(output.stdout)
+		.with_context(|| format!("`{cmd_string}` gave back non UTF-8 output"))
+}
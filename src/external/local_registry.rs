@@ -0,0 +1,215 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{cargo, sparse_index, KnownVersions, Registry};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::{
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+/// A registry that lives entirely in a local directory, so that a release can be rehearsed
+/// without any network access. The directory is laid out like a sparse index, with the `.crate`
+/// files alongside it:
+///
+/// ```text
+/// se/rd/serde                            (index file, one JSON line per version)
+/// crates/serde/serde-1.0.0.crate         (packaged crate)
+/// ```
+///
+/// "Publishing" to this registry packages the crate and copies it into the directory.
+#[derive(Debug, Clone)]
+pub struct LocalRegistry {
+	dir: PathBuf,
+}
+
+impl LocalRegistry {
+	pub fn new(dir: PathBuf) -> Self {
+		LocalRegistry { dir }
+	}
+
+	fn index_file(&self, name: &str) -> PathBuf {
+		self.dir.join(sparse_index::index_file_path(name))
+	}
+
+	fn crate_file(&self, name: &str, version: &semver::Version) -> PathBuf {
+		self.dir.join("crates").join(name).join(format!("{name}-{version}.crate"))
+	}
+
+	fn get_index_entries(&self, name: &str) -> anyhow::Result<Vec<sparse_index::IndexEntry>> {
+		let path = self.index_file(name);
+		if !path.is_file() {
+			return Ok(vec![])
+		}
+		let contents = std::fs::read_to_string(&path)
+			.with_context(|| format!("Cannot read the index file at {path:?}"))?;
+		sparse_index::parse_index_file(&contents)
+	}
+}
+
+impl Registry for LocalRegistry {
+	fn does_crate_exist(&self, name: &str, version: &semver::Version) -> anyhow::Result<bool> {
+		let version = version.to_string();
+		Ok(self.get_index_entries(name)?.iter().any(|entry| entry.vers == version))
+	}
+
+	/// There's no separate API for a local registry, so this is the same as looking in the index.
+	fn does_crate_exist_in_api(
+		&self,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<bool> {
+		self.does_crate_exist(name, version)
+	}
+
+	fn try_download_crate(
+		&self,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<Option<Vec<u8>>> {
+		let path = self.crate_file(name, version);
+		if !path.is_file() {
+			return Ok(None)
+		}
+		let bytes =
+			std::fs::read(&path).with_context(|| format!("Cannot read the crate at {path:?}"))?;
+		Ok(Some(bytes))
+	}
+
+	fn get_known_crate_versions(&self, name: &str) -> anyhow::Result<KnownVersions> {
+		sparse_index::known_versions(name, self.get_index_entries(name)?)
+	}
+
+	/// Cargo can't resolve dependencies against this registry, so when packaging a crate, any
+	/// crates that have already been published here are patched in from unpacked copies of
+	/// their latest versions. This lets crates depend on each other as they would on crates.io.
+	fn dependency_patches(&self) -> anyhow::Result<Vec<(String, PathBuf)>> {
+		let crates_dir = self.dir.join("crates");
+		if !crates_dir.is_dir() {
+			return Ok(vec![])
+		}
+
+		let mut patches = vec![];
+		for entry in std::fs::read_dir(&crates_dir)
+			.with_context(|| format!("Cannot read the crates in {crates_dir:?}"))?
+		{
+			let name = entry?.file_name().to_string_lossy().into_owned();
+			let latest = match self.get_known_crate_versions(&name)?.latest() {
+				Some(latest) => latest.version.clone(),
+				None => continue,
+			};
+
+			let unpacked_dir = self.dir.join(".unpacked");
+			let unpacked = unpacked_dir.join(format!("{name}-{latest}"));
+			if !unpacked.is_dir() {
+				let crate_file = self.crate_file(&name, &latest);
+				let file = std::fs::File::open(&crate_file)
+					.with_context(|| format!("Cannot open the crate at {crate_file:?}"))?;
+				tar::Archive::new(flate2::read::GzDecoder::new(file))
+					.unpack(&unpacked_dir)
+					.with_context(|| format!("Cannot unpack {crate_file:?}"))?;
+			}
+			patches.push((name, unpacked));
+		}
+		patches.sort();
+		Ok(patches)
+	}
+
+	fn publish_crate(
+		&self,
+		root: &Path,
+		name: &str,
+		version: &semver::Version,
+		allow_dirty: bool,
+	) -> anyhow::Result<()> {
+		if self.does_crate_exist(name, version)? {
+			anyhow::bail!("{name} {version} has already been published to {:?}", self.dir);
+		}
+
+		// Package the crate up somewhere out of the way of the workspace.
+		let target_dir = self.dir.join(".target");
+		let patches = self.dependency_patches()?;
+		let packaged =
+			cargo::package_crate(root, name, version, &target_dir, &patches, allow_dirty)?;
+		let bytes = std::fs::read(&packaged)
+			.with_context(|| format!("Cannot read the packaged crate at {packaged:?}"))?;
+
+		let crate_file = self.crate_file(name, version);
+		std::fs::create_dir_all(crate_file.parent().expect("crate file has a parent; qed"))?;
+		std::fs::write(&crate_file, &bytes)
+			.with_context(|| format!("Cannot write the crate to {crate_file:?}"))?;
+
+		// Only add the version to the index once the crate file is in place, so that
+		// anything which sees the version in the index can also download it.
+		let cksum = format!("{:x}", Sha256::digest(&bytes));
+		let entry = serde_json::json!({
+			"name": name,
+			"vers": version.to_string(),
+			"deps": [],
+			"cksum": cksum,
+			"features": {},
+			"yanked": false,
+		});
+
+		let index_file = self.index_file(name);
+		std::fs::create_dir_all(index_file.parent().expect("index file has a parent; qed"))?;
+		let mut file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&index_file)
+			.with_context(|| format!("Cannot open the index file at {index_file:?}"))?;
+		writeln!(file, "{entry}")
+			.with_context(|| format!("Cannot write to the index file at {index_file:?}"))?;
+
+		Ok(())
+	}
+
+	/// Anybody can publish anything to a local registry.
+	fn check_can_publish(
+		&self,
+		_name: &str,
+		_publisher: Option<&str>,
+	) -> anyhow::Result<Option<String>> {
+		Ok(None)
+	}
+
+	/// Yanking a crate just marks it as yanked in the index; the crate file is left in place.
+	fn yank_crate(&self, name: &str, version: &semver::Version) -> anyhow::Result<()> {
+		let index_file = self.index_file(name);
+		let contents = std::fs::read_to_string(&index_file).unwrap_or_default();
+
+		let version = version.to_string();
+		let mut found = false;
+		let mut lines = vec![];
+		for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+			let mut entry: serde_json::Value = serde_json::from_str(line)
+				.with_context(|| format!("Cannot parse the index file at {index_file:?}"))?;
+			if entry["vers"] == version {
+				entry["yanked"] = true.into();
+				found = true;
+			}
+			lines.push(format!("{entry}\n"));
+		}
+
+		if !found {
+			anyhow::bail!("{name} {version} has not been published to {:?}", self.dir);
+		}
+		std::fs::write(&index_file, lines.concat())
+			.with_context(|| format!("Cannot write to the index file at {index_file:?}"))?;
+		Ok(())
+	}
+}
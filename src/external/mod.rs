@@ -16,5 +16,15 @@
 
 //! Expose external services here.
 
+pub mod cached_registry;
 pub mod cargo;
-pub mod crates_io;
+pub mod cargo_config;
+pub mod cargo_metadata;
+pub mod git;
+pub mod http;
+pub mod local_registry;
+pub mod registry;
+pub mod sparse_index;
+pub mod sparse_registry;
+
+pub use registry::{KnownVersion, KnownVersions, Registry};
@@ -0,0 +1,108 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A registry that crates are published to. Everything that we need to know about published
+/// crates goes through this, so that we aren't tied to any one registry (or to the network).
+pub trait Registry: std::fmt::Debug + Send + Sync {
+	/// Does this version of the crate exist in the registry yet?
+	fn does_crate_exist(&self, name: &str, version: &Version) -> anyhow::Result<bool>;
+
+	/// Does this version of the crate exist according to the registry's web API yet? This can
+	/// differ from [`Registry::does_crate_exist()`] for a while after publishing, since the API
+	/// and the index are updated separately.
+	fn does_crate_exist_in_api(&self, name: &str, version: &Version) -> anyhow::Result<bool>;
+
+	/// Download the `.crate` file for a given version of a crate, returning `None` if that
+	/// version cannot be found.
+	fn try_download_crate(&self, name: &str, version: &Version) -> anyhow::Result<Option<Vec<u8>>>;
+
+	/// Which versions of this crate exist in the registry, and which of those have been yanked?
+	fn get_known_crate_versions(&self, name: &str) -> anyhow::Result<KnownVersions>;
+
+	/// Publish the given version of a crate, which lives in the workspace at `root`. If
+	/// `allow_dirty` is false, this fails if `root` has uncommitted changes.
+	fn publish_crate(
+		&self,
+		root: &Path,
+		name: &str,
+		version: &Version,
+		allow_dirty: bool,
+	) -> anyhow::Result<()>;
+
+	/// Crates which cargo can't fetch from this registry by itself, and so have to be patched
+	/// in when packaging anything that depends on them, along with where each one lives.
+	fn dependency_patches(&self) -> anyhow::Result<Vec<(String, PathBuf)>> {
+		Ok(vec![])
+	}
+
+	/// Yank the given version of a crate, so that nothing new can start depending on it.
+	fn yank_crate(&self, name: &str, version: &Version) -> anyhow::Result<()>;
+
+	/// Check whether we'd be allowed to publish the given crate, as the given user if one is
+	/// given or else as whoever the configured token belongs to. Returns the reason why not if
+	/// we wouldn't be.
+	fn check_can_publish(
+		&self,
+		name: &str,
+		publisher: Option<&str>,
+	) -> anyhow::Result<Option<String>>;
+}
+
+/// The versions of a crate that have been published to a registry. Yanked versions are still
+/// included, since their version numbers can never be published again.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KnownVersions {
+	versions: Vec<KnownVersion>,
+}
+
+/// A single published version of a crate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownVersion {
+	pub version: Version,
+	pub yanked: bool,
+}
+
+impl KnownVersions {
+	/// Has this version been published (whether or not it has since been yanked)?
+	pub fn contains(&self, version: &Version) -> bool {
+		self.versions.iter().any(|v| &v.version == version)
+	}
+
+	/// Has this version been published and then yanked?
+	pub fn is_yanked(&self, version: &Version) -> bool {
+		self.versions.iter().any(|v| &v.version == version && v.yanked)
+	}
+
+	/// The highest version that has been published, yanked or not.
+	pub fn latest(&self) -> Option<&KnownVersion> {
+		self.versions.iter().max_by(|a, b| a.version.cmp(&b.version))
+	}
+
+	/// All of the versions that have been yanked.
+	pub fn yanked(&self) -> impl Iterator<Item = &Version> {
+		self.versions.iter().filter(|v| v.yanked).map(|v| &v.version)
+	}
+}
+
+impl FromIterator<KnownVersion> for KnownVersions {
+	fn from_iter<T: IntoIterator<Item = KnownVersion>>(iter: T) -> Self {
+		KnownVersions { versions: iter.into_iter().collect() }
+	}
+}
@@ -0,0 +1,80 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Helpers for working with the sparse index protocol that cargo uses to look up crates. See
+//! <https://doc.rust-lang.org/cargo/reference/registry-index.html>.
+
+use super::{KnownVersion, KnownVersions};
+use anyhow::Context;
+use serde::Deserialize;
+
+/// A single line from a crate's index file; one of these exists for every published version.
+#[allow(unused)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexEntry {
+	pub vers: String,
+	#[serde(default)]
+	pub yanked: bool,
+	pub cksum: String,
+}
+
+/// The path to the index file for a given crate, relative to the root of the index. Crate names
+/// are lowercased, and the file is nested under a prefix based on the length of the name:
+///
+/// ```text
+/// a -> 1/a
+/// ab -> 2/ab
+/// abc -> 3/a/abc
+/// serde -> se/rd/serde
+/// ```
+pub fn index_file_path(name: &str) -> String {
+	let name = name.to_lowercase();
+	format!("{}/{name}", index_prefix(&name))
+}
+
+/// The directory that a crate's index file lives in, preserving the case of the name given.
+pub fn index_prefix(name: &str) -> String {
+	match name.len() {
+		1 => "1".to_owned(),
+		2 => "2".to_owned(),
+		3 => format!("3/{}", &name[..1]),
+		_ => format!("{}/{}", &name[..2], &name[2..4]),
+	}
+}
+
+/// Parse the contents of an index file, which contains one JSON object per line.
+pub fn parse_index_file(contents: &str) -> anyhow::Result<Vec<IndexEntry>> {
+	contents
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| {
+			serde_json::from_str(line)
+				.with_context(|| format!("Cannot parse index entry from line: {line}"))
+		})
+		.collect()
+}
+
+/// Pull out the versions (and whether each is yanked) from the index entries for a crate.
+pub fn known_versions(name: &str, entries: Vec<IndexEntry>) -> anyhow::Result<KnownVersions> {
+	entries
+		.into_iter()
+		.map(|entry| {
+			let version = semver::Version::parse(&entry.vers)
+				.with_context(|| format!("Cannot parse version {} of {name}", entry.vers))?;
+			Ok(KnownVersion { version, yanked: entry.yanked })
+		})
+		.collect()
+}
@@ -0,0 +1,537 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{cargo, cargo_config, http::HttpClient, sparse_index, KnownVersions, Registry};
+use anyhow::Context;
+use serde::Deserialize;
+use std::{path::Path, sync::OnceLock};
+
+const CRATES_IO_INDEX: &str = "https://index.crates.io";
+const CRATES_IO_DL: &str = "https://static.crates.io/crates";
+const CRATES_IO_API: &str = "https://crates.io";
+
+/// A registry that we talk to using the sparse index protocol, like crates.io.
+#[derive(Debug, Clone)]
+pub struct SparseRegistry {
+	client: HttpClient,
+	/// The name of the registry in the cargo config, or `None` for crates.io.
+	name: Option<String>,
+	/// The root URL of the sparse index, without a trailing slash.
+	index: String,
+	/// The URL (or URL template) to download `.crate` files from.
+	dl: String,
+	/// The root URL of the web API, if the registry has one.
+	api: Option<String>,
+	/// A token to authenticate with, if the registry requires one even for downloads.
+	token: Option<String>,
+	/// The user that the publish token belongs to, once we've asked the API about it, or why
+	/// it wouldn't say.
+	token_owner: OnceLock<Result<String, String>>,
+	/// What the publish token is allowed to do, once we've asked the API about it, or why it
+	/// wouldn't say.
+	token_scopes: OnceLock<Result<TokenScopes, String>>,
+}
+
+/// The contents of the `config.json` file at the root of a sparse index.
+#[derive(Deserialize)]
+struct IndexConfig {
+	dl: String,
+	api: Option<String>,
+	#[serde(default, rename = "auth-required")]
+	auth_required: bool,
+}
+
+/// The response from the web API's `/api/v1/me` endpoint.
+#[derive(Deserialize)]
+struct Me {
+	user: User,
+}
+
+#[derive(Deserialize)]
+struct User {
+	login: String,
+}
+
+/// The response from the web API's `/api/v1/tokens/current` endpoint.
+#[derive(Deserialize)]
+struct CurrentToken {
+	api_token: TokenScopes,
+}
+
+/// The scopes that a token is limited to. A missing list means no limit.
+#[derive(Debug, Clone, Deserialize)]
+struct TokenScopes {
+	/// Names of the crates the token can be used with. A name ending in `*` matches every
+	/// crate that starts with the rest of it.
+	crate_scopes: Option<Vec<String>>,
+	/// The actions the token can be used for, like "publish-new" and "publish-update".
+	endpoint_scopes: Option<Vec<String>>,
+}
+
+impl TokenScopes {
+	/// Can the token publish the crate given? Returns a description of the problem if not.
+	fn check(&self, name: &str, is_new: bool) -> Option<String> {
+		let endpoint = if is_new { "publish-new" } else { "publish-update" };
+		if let Some(scopes) = &self.endpoint_scopes {
+			if !scopes.iter().any(|scope| scope == endpoint) {
+				return Some(format!(
+					"the token is not allowed to {} (it is limited to {})",
+					if is_new { "publish new crates" } else { "publish new versions" },
+					scopes.join(", ")
+				))
+			}
+		}
+		if let Some(scopes) = &self.crate_scopes {
+			let matches = |scope: &String| match scope.strip_suffix('*') {
+				Some(prefix) => name.starts_with(prefix),
+				None => name == scope,
+			};
+			if !scopes.iter().any(matches) {
+				return Some(format!("the token is limited to other crates ({})", scopes.join(", ")))
+			}
+		}
+		None
+	}
+}
+
+/// The response from the web API's `/api/v1/crates/<name>` endpoint.
+#[derive(Deserialize)]
+struct CrateInfo {
+	#[serde(rename = "crate")]
+	krate: CrateName,
+}
+
+#[derive(Deserialize)]
+struct CrateName {
+	name: String,
+}
+
+/// The response from the web API's `/api/v1/crates/<name>/owners` endpoint.
+#[derive(Deserialize)]
+struct Owners {
+	users: Vec<Owner>,
+}
+
+#[derive(Deserialize)]
+struct Owner {
+	login: String,
+	/// Either "user" or "team".
+	kind: String,
+}
+
+impl SparseRegistry {
+	/// The public crates.io registry.
+	pub fn crates_io(client: HttpClient) -> Self {
+		SparseRegistry {
+			client,
+			name: None,
+			index: CRATES_IO_INDEX.to_owned(),
+			dl: CRATES_IO_DL.to_owned(),
+			api: Some(CRATES_IO_API.to_owned()),
+			token: None,
+			token_owner: OnceLock::new(),
+			token_scopes: OnceLock::new(),
+		}
+	}
+
+	/// Talk to different endpoints than usual, for instance to go through a mirror. Anything
+	/// not given is left as it is.
+	pub fn with_endpoints(
+		mut self,
+		index: Option<String>,
+		dl: Option<String>,
+		api: Option<String>,
+	) -> Self {
+		if let Some(index) = index {
+			self.index = index.trim_end_matches('/').to_owned();
+		}
+		if let Some(dl) = dl {
+			self.dl = dl;
+		}
+		if let Some(api) = api {
+			self.api = Some(api.trim_end_matches('/').to_owned());
+		}
+		self
+	}
+
+	/// A registry configured under `[registries.<name>]` in the cargo config for the workspace
+	/// at `root`. Only sparse registries are supported.
+	pub fn from_cargo_config(root: &Path, name: &str, client: HttpClient) -> anyhow::Result<Self> {
+		let index_url = cargo_config::registry_index_url(root, name)?;
+		let index = match index_url.strip_prefix("sparse+") {
+			Some(index) => index.trim_end_matches('/').to_owned(),
+			None => anyhow::bail!(
+				"The registry '{name}' uses the index {index_url}, but only sparse indexes are supported"
+			),
+		};
+
+		let url = format!("{index}/config.json");
+		let res = client
+			.send(client.get(&url))
+			.with_context(|| format!("Cannot get the config for registry '{name}'"))?;
+		if !res.status().is_success() {
+			anyhow::bail!("Non-200 status trying to connect to {url} ({})", res.status());
+		}
+		let config: IndexConfig = res
+			.json()
+			.with_context(|| format!("Cannot parse the config for registry '{name}'"))?;
+
+		let token = if config.auth_required {
+			let token = cargo_config::registry_token(Some(name))?;
+			if token.is_none() {
+				anyhow::bail!("The registry '{name}' requires authentication, but no token is configured for it");
+			}
+			token
+		} else {
+			None
+		};
+
+		Ok(SparseRegistry {
+			client,
+			name: Some(name.to_owned()),
+			index,
+			dl: config.dl,
+			api: config.api.map(|api| api.trim_end_matches('/').to_owned()),
+			token,
+			token_owner: OnceLock::new(),
+			token_scopes: OnceLock::new(),
+		})
+	}
+
+	/// Work out where to download a `.crate` file from, given the `dl` template from the index
+	/// config. See <https://doc.rust-lang.org/cargo/reference/registry-index.html#index-configuration>.
+	fn download_url(
+		&self,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<Option<String>> {
+		const MARKERS: [&str; 5] =
+			["{crate}", "{version}", "{prefix}", "{lowerprefix}", "{sha256-checksum}"];
+
+		let version = version.to_string();
+		if !MARKERS.iter().any(|marker| self.dl.contains(marker)) {
+			return Ok(Some(format!("{}/{name}/{version}/download", self.dl)))
+		}
+
+		let mut url = self.dl.clone();
+		if url.contains("{sha256-checksum}") {
+			// The checksum is only known to the index, so look the version up there first.
+			let entries = self.get_index_entries(name, false)?;
+			let entry = match entries.iter().find(|entry| entry.vers == version) {
+				Some(entry) => entry,
+				None => return Ok(None),
+			};
+			url = url.replace("{sha256-checksum}", &entry.cksum);
+		}
+
+		Ok(Some(
+			url.replace("{crate}", name)
+				.replace("{version}", &version)
+				.replace("{prefix}", &sparse_index::index_prefix(name))
+				.replace("{lowerprefix}", &sparse_index::index_prefix(&name.to_lowercase())),
+		))
+	}
+
+	/// Make a GET request to the web API, authenticating with the token given.
+	fn get_api(
+		&self,
+		api: &str,
+		path: &str,
+		token: &str,
+	) -> anyhow::Result<reqwest::blocking::Response> {
+		let url = format!("{api}/api/v1/{path}");
+		let req = self.client.get(&url).header("Authorization", token);
+		self.client.send(req).with_context(|| format!("Cannot connect to {url}"))
+	}
+
+	/// Ask the web API which user the publish token belongs to. Some registries (crates.io
+	/// among them) only answer this for browser sessions, in which case the publisher has to be
+	/// given with `--publisher` instead.
+	fn token_owner(&self, api: &str, token: &str) -> anyhow::Result<&str> {
+		let owner = self.token_owner.get_or_init(|| {
+			let owner = self.get_api(api, "me", token).and_then(|res| {
+				if !res.status().is_success() {
+					anyhow::bail!("{} from {api}/api/v1/me", res.status());
+				}
+				let me: Me =
+					res.json().context("Cannot parse the user that the token belongs to")?;
+				Ok(me.user.login)
+			});
+			owner.map_err(|e| format!("{e:#}"))
+		});
+		match owner {
+			Ok(login) => Ok(login),
+			Err(e) => anyhow::bail!(
+				"Cannot tell which user the publish token belongs to ({e}), so crate owners can't be checked; pass it with --publisher, or skip the check with --no-ownership-check"
+			),
+		}
+	}
+
+	/// Ask the web API what the publish token is allowed to do.
+	fn token_scopes(&self, api: &str, token: &str) -> anyhow::Result<&TokenScopes> {
+		let scopes = self.token_scopes.get_or_init(|| {
+			let scopes = self.get_api(api, "tokens/current", token).and_then(|res| {
+				if !res.status().is_success() {
+					anyhow::bail!("{} from {api}/api/v1/tokens/current", res.status());
+				}
+				let current: CurrentToken =
+					res.json().context("Cannot parse the scopes of the token")?;
+				Ok(current.api_token)
+			});
+			scopes.map_err(|e| format!("{e:#}"))
+		});
+		match scopes {
+			Ok(scopes) => Ok(scopes),
+			Err(e) => anyhow::bail!(
+				"Cannot tell what the publish token is allowed to do ({e}), so its scopes can't be checked; skip the check with --no-ownership-check"
+			),
+		}
+	}
+
+	/// Fetch and parse the sparse index file for a crate. A crate that has never been published
+	/// has no index file, and so no entries.
+	fn get_index_entries(
+		&self,
+		name: &str,
+		no_cache: bool,
+	) -> anyhow::Result<Vec<sparse_index::IndexEntry>> {
+		let url = format!("{}/{}", self.index, sparse_index::index_file_path(name));
+		let mut req = self.client.get(&url);
+		if no_cache {
+			req = req.header("Cache-Control", "no-cache");
+		}
+		if let Some(token) = &self.token {
+			req = req.header("Authorization", token);
+		}
+		let res = self
+			.client
+			.send(req)
+			.with_context(|| format!("Cannot get index entries for {name}"))?;
+
+		// These are the status codes that cargo treats as "this crate does not exist".
+		if matches!(res.status().as_u16(), 404 | 410 | 451) {
+			return Ok(vec![])
+		}
+		if !res.status().is_success() {
+			anyhow::bail!("Non-200 status trying to connect to {url} ({})", res.status());
+		}
+
+		let contents =
+			res.text().with_context(|| format!("Cannot read index entries for {name}"))?;
+		sparse_index::parse_index_file(&contents)
+	}
+}
+
+impl Registry for SparseRegistry {
+	/// Is the given version of the crate in the sparse index yet? This is what cargo looks at
+	/// when resolving dependencies, so once a version appears here, crates depending on it can
+	/// be published.
+	fn does_crate_exist(&self, name: &str, version: &semver::Version) -> anyhow::Result<bool> {
+		// We'll be polling this after publishing, so make sure we don't get handed a stale copy.
+		let entries = self.get_index_entries(name, true)?;
+		let version = version.to_string();
+		Ok(entries.iter().any(|entry| entry.vers == version))
+	}
+
+	/// Does the web API know about the given version of the crate yet? This is what crates.io
+	/// shows on its website.
+	fn does_crate_exist_in_api(
+		&self,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<bool> {
+		let api = match &self.api {
+			Some(api) => api,
+			None => anyhow::bail!("This registry has no web API to check for {name} {version} in"),
+		};
+
+		let url = format!("{api}/api/v1/crates/{name}/{version}");
+		let mut req = self.client.get(&url).header("Cache-Control", "no-cache");
+		if let Some(token) = &self.token {
+			req = req.header("Authorization", token);
+		}
+		let res = self
+			.client
+			.send(req)
+			.with_context(|| format!("Cannot check the API for {name} {version}"))?;
+
+		if res.status().as_u16() == 404 {
+			return Ok(false)
+		}
+		if !res.status().is_success() {
+			anyhow::bail!("Non-200 status trying to connect to {url} ({})", res.status());
+		}
+		Ok(true)
+	}
+
+	/// Download a crate from the registry.
+	fn try_download_crate(
+		&self,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<Option<Vec<u8>>> {
+		let url = match self.download_url(name, version)? {
+			Some(url) => url,
+			None => return Ok(None),
+		};
+
+		let mut req = self.client.get(&url);
+		if let Some(token) = &self.token {
+			req = req.header("Authorization", token);
+		}
+		let res = self.client.send(req).with_context(|| format!("Cannot download {name}"))?;
+
+		if !res.status().is_success() {
+			return Ok(None)
+		}
+
+		Ok(Some(res.bytes()?.to_vec()))
+	}
+
+	/// Which versions of this crate exist in the registry?
+	fn get_known_crate_versions(&self, name: &str) -> anyhow::Result<KnownVersions> {
+		sparse_index::known_versions(name, self.get_index_entries(name, false)?)
+	}
+
+	fn publish_crate(
+		&self,
+		root: &Path,
+		name: &str,
+		_version: &semver::Version,
+		allow_dirty: bool,
+	) -> anyhow::Result<()> {
+		cargo::publish_crate(root, name, self.name.as_deref(), allow_dirty)
+	}
+
+	fn yank_crate(&self, name: &str, version: &semver::Version) -> anyhow::Result<()> {
+		cargo::yank_crate(name, version, self.name.as_deref())
+	}
+
+	/// Look the crate up in the web API. A new crate can be published if nothing else has its
+	/// name, and an existing one if the publisher is one of its owners. Either way, the token
+	/// has to be scoped to allow it. If the API won't tell us any of this, that's an error rather
+	/// than a pass.
+	fn check_can_publish(
+		&self,
+		name: &str,
+		publisher: Option<&str>,
+	) -> anyhow::Result<Option<String>> {
+		let api = match &self.api {
+			Some(api) => api,
+			None => anyhow::bail!(
+				"The registry has no web API, so we can't check who owns {name}; skip the check with --no-ownership-check"
+			),
+		};
+		let token = match cargo_config::registry_token(self.name.as_deref())? {
+			Some(token) => token,
+			None => anyhow::bail!("There is no token configured to publish crates with"),
+		};
+
+		// The API treats names that differ only in case or in `-` vs `_` as the same crate,
+		// just like it does when publishing, so this also finds names that are taken.
+		let res = self.get_api(api, &format!("crates/{name}"), &token)?;
+		let is_new = res.status().as_u16() == 404;
+		if !is_new {
+			if !res.status().is_success() {
+				anyhow::bail!("Non-200 status trying to look up {name} ({})", res.status());
+			}
+			let info: CrateInfo =
+				res.json().with_context(|| format!("Cannot parse the details of {name}"))?;
+			if info.krate.name != name {
+				return Ok(Some(format!("the name is taken by the crate '{}'", info.krate.name)))
+			}
+		}
+
+		if let Some(problem) = self.token_scopes(api, &token)?.check(name, is_new) {
+			return Ok(Some(problem))
+		}
+		if is_new {
+			return Ok(None)
+		}
+
+		let publisher = match publisher {
+			Some(publisher) => publisher,
+			None => self.token_owner(api, &token)?,
+		};
+
+		let res = self.get_api(api, &format!("crates/{name}/owners"), &token)?;
+		if !res.status().is_success() {
+			anyhow::bail!("Non-200 status trying to get the owners of {name} ({})", res.status());
+		}
+		let owners: Owners =
+			res.json().with_context(|| format!("Cannot parse the owners of {name}"))?;
+
+		let is_owner = owners
+			.users
+			.iter()
+			.any(|owner| owner.kind == "user" && owner.login == publisher);
+		if is_owner {
+			return Ok(None)
+		}
+
+		let logins: Vec<&str> = owners.users.iter().map(|owner| &*owner.login).collect();
+		if owners.users.iter().any(|owner| owner.kind == "team") {
+			// We can't see who is in a team, so give the publisher the benefit of the doubt.
+			log::warn!(
+				"{name}: {publisher} is not an owner, but may be in one of the owning teams ({})",
+				logins.join(", ")
+			);
+			return Ok(None)
+		}
+		Ok(Some(format!("{publisher} is not an owner (the owners are {})", logins.join(", "))))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn scopes(json: &str) -> TokenScopes {
+		serde_json::from_str(json).unwrap()
+	}
+
+	#[test]
+	fn unscoped_tokens_can_publish_anything() {
+		let scopes = scopes(r#"{ "crate_scopes": null, "endpoint_scopes": null }"#);
+		assert_eq!(scopes.check("foo", true), None);
+		assert_eq!(scopes.check("foo", false), None);
+	}
+
+	#[test]
+	fn crate_scopes_match_exactly_or_by_wildcard_prefix() {
+		let scopes = scopes(r#"{ "crate_scopes": ["serde", "tokio-*"], "endpoint_scopes": null }"#);
+		assert_eq!(scopes.check("serde", false), None);
+		assert_eq!(scopes.check("tokio-util", false), None);
+		assert_eq!(scopes.check("tokio-", false), None);
+		assert!(scopes.check("serde_json", false).is_some());
+		assert!(scopes.check("tokio", false).is_some());
+		assert!(scopes.check("my-tokio-util", false).is_some());
+	}
+
+	#[test]
+	fn a_bare_wildcard_matches_every_crate() {
+		let scopes = scopes(r#"{ "crate_scopes": ["*"], "endpoint_scopes": null }"#);
+		assert_eq!(scopes.check("anything", true), None);
+	}
+
+	#[test]
+	fn endpoint_scopes_distinguish_new_crates_from_updates() {
+		let scopes =
+			scopes(r#"{ "crate_scopes": ["foo*"], "endpoint_scopes": ["publish-update"] }"#);
+		assert_eq!(scopes.check("foo-bar", false), None);
+		assert!(scopes.check("foo-bar", true).unwrap().contains("publish new crates"));
+	}
+}
@@ -0,0 +1,191 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::version::Version;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Where the journal lives, relative to the state directory. This is the workspace's even when
+/// publishing from a staging area, because it has to survive until the run is resumed.
+const JOURNAL_PATH: &str = "publish-state.json";
+
+/// A record of how far through publishing each crate we've got, which is saved to disk after
+/// every step so that an interrupted publish run can pick up where it left off.
+#[derive(Debug)]
+pub struct Journal {
+	path: PathBuf,
+	contents: JournalContents,
+}
+
+/// What's saved to disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalContents {
+	/// Whether the run gave up and yanked what it had published. There's nothing left to resume
+	/// in that case; the journal is only kept if something couldn't be yanked.
+	#[serde(default)]
+	rolled_back: bool,
+	crates: Vec<JournalEntry>,
+}
+
+/// The state of a single crate that we plan to publish.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+	pub name: String,
+	pub version: Version,
+	pub state: CrateState,
+	/// Whether this run uploaded the crate, rather than finding it already in the registry. This
+	/// isn't saved, so that rolling back a resumed run doesn't touch what earlier runs uploaded.
+	#[serde(skip)]
+	pub uploaded: bool,
+	/// Whether the crate has been yanked again, by rolling back.
+	#[serde(default)]
+	pub yanked: bool,
+}
+
+/// How far through publishing a crate we've got. These are in the order that they happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrateState {
+	/// We intend to publish this crate, but haven't touched it yet.
+	Planned,
+	/// The dev dependencies have been stripped from the crate's manifest.
+	Stripped,
+	/// `cargo publish` has succeeded for the crate.
+	Published,
+	/// The crate can be seen in the registry, so crates depending on it can be published.
+	Visible,
+}
+
+impl Journal {
+	/// The path to the journal kept in `state_dir`.
+	pub fn path(state_dir: &Path) -> PathBuf {
+		state_dir.join(JOURNAL_PATH)
+	}
+
+	/// Start a new journal in `state_dir`, planning to publish the crates given in
+	/// order. This is saved to disk straight away.
+	pub fn create(state_dir: &Path, crates: Vec<(String, Version)>) -> anyhow::Result<Journal> {
+		let crates = crates
+			.into_iter()
+			.map(|(name, version)| JournalEntry {
+				name,
+				version,
+				state: CrateState::Planned,
+				uploaded: false,
+				yanked: false,
+			})
+			.collect();
+		let contents = JournalContents { rolled_back: false, crates };
+		let journal = Journal { path: Journal::path(state_dir), contents };
+		journal.save()?;
+		Ok(journal)
+	}
+
+	/// Load the journal kept in `state_dir`, if there is one.
+	pub fn load(state_dir: &Path) -> anyhow::Result<Option<Journal>> {
+		let path = Journal::path(state_dir);
+		if !path.exists() {
+			return Ok(None)
+		}
+
+		let contents = std::fs::read(&path)
+			.with_context(|| format!("Cannot read the publish journal at {path:?}"))?;
+		let contents = serde_json::from_slice(&contents)
+			.with_context(|| format!("Cannot parse the publish journal at {path:?}"))?;
+		Ok(Some(Journal { path, contents }))
+	}
+
+	/// Where this journal is saved.
+	pub fn file_path(&self) -> &Path {
+		&self.path
+	}
+
+	/// The crates in this journal, in the order that they are to be published.
+	pub fn entries(&self) -> &[JournalEntry] {
+		&self.contents.crates
+	}
+
+	/// Has the run that this journal records been rolled back?
+	pub fn rolled_back(&self) -> bool {
+		self.contents.rolled_back
+	}
+
+	/// How far through publishing the crate given are we?
+	pub fn state(&self, name: &str) -> Option<CrateState> {
+		self.contents.crates.iter().find(|c| c.name == name).map(|c| c.state)
+	}
+
+	/// Record that the crate given has reached some new state, saving this to disk.
+	pub fn set_state(&mut self, name: &str, state: CrateState) -> anyhow::Result<()> {
+		self.entry_mut(name)?.state = state;
+		self.save()
+	}
+
+	/// Record that the crate given has been published, and that it was this run that uploaded it
+	/// (rather than it already being in the registry), saving this to disk.
+	pub fn set_uploaded(&mut self, name: &str) -> anyhow::Result<()> {
+		let entry = self.entry_mut(name)?;
+		entry.state = CrateState::Published;
+		entry.uploaded = true;
+		self.save()
+	}
+
+	/// Record that the run is being rolled back, saving this to disk.
+	pub fn set_rolled_back(&mut self) -> anyhow::Result<()> {
+		self.contents.rolled_back = true;
+		self.save()
+	}
+
+	/// Record that the crate given has been yanked by rolling back, saving this to disk.
+	pub fn set_yanked(&mut self, name: &str) -> anyhow::Result<()> {
+		self.entry_mut(name)?.yanked = true;
+		self.save()
+	}
+
+	/// Remove the journal from disk, once everything in it has been published.
+	pub fn remove(self) -> anyhow::Result<()> {
+		let path = &self.path;
+		std::fs::remove_file(path)
+			.with_context(|| format!("Cannot remove the publish journal at {path:?}"))?;
+
+		// Tidy up the directory too, if nothing else is in it.
+		let _ = std::fs::remove_dir(path.parent().expect("journal path has a parent; qed"));
+		Ok(())
+	}
+
+	fn entry_mut(&mut self, name: &str) -> anyhow::Result<&mut JournalEntry> {
+		match self.contents.crates.iter_mut().find(|c| c.name == name) {
+			Some(entry) => Ok(entry),
+			None => anyhow::bail!("Crate '{name}' is not in the publish journal"),
+		}
+	}
+
+	fn save(&self) -> anyhow::Result<()> {
+		let path = &self.path;
+		let dir = path.parent().expect("journal path has a parent; qed");
+		std::fs::create_dir_all(dir).with_context(|| format!("Cannot create {dir:?}"))?;
+
+		// Write to a temporary file and move it into place, so that being interrupted
+		// part way through never leaves us with a corrupt journal.
+		let tmp_path = path.with_extension("json.tmp");
+		std::fs::write(&tmp_path, serde_json::to_vec_pretty(&self.contents)?)
+			.with_context(|| format!("Cannot write the publish journal to {tmp_path:?}"))?;
+		std::fs::rename(&tmp_path, path)
+			.with_context(|| format!("Cannot move the publish journal into place at {path:?}"))?;
+		Ok(())
+	}
+}
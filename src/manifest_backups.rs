@@ -0,0 +1,120 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Where backups live, relative to the state directory.
+const BACKUPS_PATH: &str = "manifests";
+
+/// Copies of `Cargo.toml` files taken before we modify them for publishing, so that they can be
+/// put back exactly as they were afterwards. These are kept on disk rather than in memory, so
+/// that they survive the process being killed.
+#[derive(Debug, Clone)]
+pub struct ManifestBackups {
+	dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Backup {
+	/// Where the manifest lives.
+	path: PathBuf,
+	/// The original contents of the manifest.
+	contents: String,
+}
+
+impl ManifestBackups {
+	/// Backups kept in `state_dir`.
+	pub fn new(state_dir: &Path) -> Self {
+		ManifestBackups { dir: state_dir.join(BACKUPS_PATH) }
+	}
+
+	/// Take a copy of the manifest for the crate given, unless we already have one. An existing
+	/// backup is never overwritten, since the manifest may already have been modified.
+	pub fn save(&self, name: &str, path: &Path) -> anyhow::Result<()> {
+		let backup_path = self.backup_path(name);
+		if backup_path.exists() {
+			return Ok(())
+		}
+
+		let contents = std::fs::read_to_string(path)
+			.with_context(|| format!("Cannot read the Cargo.toml at {path:?}"))?;
+		let backup = Backup { path: path.to_path_buf(), contents };
+
+		std::fs::create_dir_all(&self.dir)
+			.with_context(|| format!("Cannot create {:?}", self.dir))?;
+		let tmp_path = backup_path.with_extension("json.tmp");
+		std::fs::write(&tmp_path, serde_json::to_vec(&backup)?)
+			.with_context(|| format!("Cannot write a backup of {name}'s Cargo.toml"))?;
+		std::fs::rename(&tmp_path, &backup_path)
+			.with_context(|| format!("Cannot write a backup of {name}'s Cargo.toml"))?;
+		Ok(())
+	}
+
+	/// Put the manifest for the crate given back how it was, and remove the backup. Returns
+	/// `false` if there was no backup to restore.
+	pub fn restore(&self, name: &str) -> anyhow::Result<bool> {
+		let backup_path = self.backup_path(name);
+		if !backup_path.exists() {
+			return Ok(false)
+		}
+
+		let backup: Backup = {
+			let contents = std::fs::read(&backup_path)
+				.with_context(|| format!("Cannot read the backup at {backup_path:?}"))?;
+			serde_json::from_slice(&contents)
+				.with_context(|| format!("Cannot parse the backup at {backup_path:?}"))?
+		};
+		std::fs::write(&backup.path, backup.contents)
+			.with_context(|| format!("Cannot restore the Cargo.toml at {:?}", backup.path))?;
+		std::fs::remove_file(&backup_path)
+			.with_context(|| format!("Cannot remove the backup at {backup_path:?}"))?;
+
+		// Tidy up the directory too, if nothing else is in it.
+		let _ = std::fs::remove_dir(&self.dir);
+		Ok(true)
+	}
+
+	/// Restore every manifest that we have a backup of, returning the names of the crates that
+	/// were restored.
+	pub fn restore_all(&self) -> anyhow::Result<Vec<String>> {
+		let entries = match std::fs::read_dir(&self.dir) {
+			Ok(entries) => entries,
+			Err(_) => return Ok(vec![]),
+		};
+
+		let mut restored = vec![];
+		for entry in entries {
+			let path = entry?.path();
+			if path.extension().and_then(|e| e.to_str()) != Some("json") {
+				continue
+			}
+			let name = match path.file_stem().and_then(|s| s.to_str()) {
+				Some(name) => name.to_owned(),
+				None => continue,
+			};
+			if self.restore(&name)? {
+				restored.push(name);
+			}
+		}
+		Ok(restored)
+	}
+
+	fn backup_path(&self, name: &str) -> PathBuf {
+		self.dir.join(format!("{name}.json"))
+	}
+}
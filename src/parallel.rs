@@ -0,0 +1,48 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::Mutex;
+
+/// Call `f` on every item, using at most `jobs` threads at once, and hand back the results in the
+/// same order as the items were given.
+pub fn map_concurrently<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+	T: Sync,
+	R: Send,
+	F: Fn(&T) -> R + Sync,
+{
+	let next_item = Mutex::new(items.iter().enumerate());
+	let results = Mutex::new(Vec::with_capacity(items.len()));
+
+	std::thread::scope(|s| {
+		for _ in 0..jobs.clamp(1, items.len().max(1)) {
+			s.spawn(|| loop {
+				// Only hold the lock long enough to take the next item.
+				let next = next_item.lock().expect("no panics while locked; qed").next();
+				let (idx, item) = match next {
+					Some(next) => next,
+					None => break,
+				};
+				let res = f(item);
+				results.lock().expect("no panics while locked; qed").push((idx, res));
+			});
+		}
+	});
+
+	let mut results = results.into_inner().expect("no panics while locked; qed");
+	results.sort_by_key(|(idx, _)| *idx);
+	results.into_iter().map(|(_, res)| res).collect()
+}
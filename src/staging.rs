@@ -0,0 +1,215 @@
+// Copyright 2019-2022 Parity Technologies (UK) Ltd.
+// This file is part of subpub.
+//
+// subpub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// subpub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with subpub.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+	crate_details,
+	external::{cargo, git},
+};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Files that cargo generates when packaging a crate, rather than taking from its sources.
+const GENERATED_FILES: [&str; 3] = ["Cargo.toml.orig", ".cargo_vcs_info.json", "Cargo.lock"];
+
+/// Fields in `[package]` which point at files that may live outside of the crate directory.
+const PACKAGE_FILE_FIELDS: [&str; 2] = ["readme", "license-file"];
+
+/// A scratch directory that crates are copied into before being published, so that we can strip
+/// their dev dependencies without touching the workspace. Everything in it is removed when this
+/// is dropped.
+pub struct StagingArea {
+	dir: tempfile::TempDir,
+	/// If the workspace is in a git repository, crates are staged in a worktree of it.
+	checkout: Option<Checkout>,
+}
+
+/// Everything needed to tidy up a staging area without dropping it, for when we're about to
+/// exit without running destructors (on Ctrl-C, say).
+#[derive(Debug, Clone)]
+pub struct StagingCleanup {
+	dir: PathBuf,
+	checkout: Option<Checkout>,
+}
+
+/// An empty worktree of the repository that the workspace is in. Crates are staged in it at
+/// the same place that they live in the repository, so that cargo records the same commit and
+/// path in `.cargo_vcs_info.json` for them as it would if they were published from the workspace.
+#[derive(Debug, Clone)]
+struct Checkout {
+	repo: PathBuf,
+	dir: PathBuf,
+}
+
+impl StagingArea {
+	/// Create a staging area for crates in the workspace at `workspace_root`.
+	pub fn new(workspace_root: &Path) -> anyhow::Result<StagingArea> {
+		let dir = tempfile::Builder::new()
+			.prefix("subpub-")
+			.tempdir()
+			.context("Cannot create a staging directory")?;
+
+		let checkout = match git::repo_root(workspace_root)? {
+			Some(repo) => {
+				let repo = repo
+					.canonicalize()
+					.with_context(|| format!("Cannot find the git repository at {repo:?}"))?;
+				let checkout_dir = dir.path().join("checkout");
+				match git::add_empty_worktree(&repo, &checkout_dir) {
+					Ok(()) => Some(Checkout { repo, dir: checkout_dir }),
+					// A repository without any commits yet, for instance. Cargo won't record
+					// anything about it either, so nothing is lost by staging crates elsewhere.
+					Err(e) => {
+						log::warn!("Cannot stage crates in a worktree of {repo:?}, so they won't include VCS info: {e:?}");
+						None
+					},
+				}
+			},
+			None => None,
+		};
+
+		Ok(StagingArea { dir, checkout })
+	}
+
+	/// A target directory that can be shared by anything built from the staging area.
+	pub fn target_dir(&self) -> PathBuf {
+		self.dir.path().join("target")
+	}
+
+	/// What to do to tidy this staging area up if it can't be dropped.
+	pub fn cleanup(&self) -> StagingCleanup {
+		StagingCleanup { dir: self.dir.path().to_path_buf(), checkout: self.checkout.clone() }
+	}
+
+	/// Check that the crate in the workspace at `root` has no uncommitted changes. The staged
+	/// copy is published as if it came from the current commit, so anything else in it would be
+	/// published without any record of it. Workspaces that aren't in git can't be checked.
+	pub fn check_committed(&self, root: &Path, name: &str) -> anyhow::Result<()> {
+		let checkout = match &self.checkout {
+			Some(checkout) => checkout,
+			None => return Ok(()),
+		};
+		let changes = git::uncommitted_changes(&checkout.repo, root)?;
+		if !changes.is_empty() {
+			anyhow::bail!(
+				"{name} has uncommitted changes, which would be published without being committed:\n{}",
+				changes.join("\n")
+			);
+		}
+		Ok(())
+	}
+
+	/// Copy the crate in the workspace at `root` into the staging area, returning the directory
+	/// that it was staged in. Only the files that cargo would package are copied, and the copied
+	/// manifest has anything it inherits from the workspace manifest at `workspace_toml` filled
+	/// in, its dev dependencies stripped and any paths in it made absolute, so that it still
+	/// points at the same things from its new home.
+	pub fn stage(
+		&self,
+		root: &Path,
+		workspace_toml: &Path,
+		name: &str,
+		version: &semver::Version,
+	) -> anyhow::Result<PathBuf> {
+		let root = root
+			.canonicalize()
+			.with_context(|| format!("Cannot find the crate directory {root:?}"))?;
+
+		// Start from scratch, in case an earlier attempt left something here.
+		let path_in_repo = self
+			.checkout
+			.as_ref()
+			.and_then(|c| root.strip_prefix(&c.repo).ok().map(|path| c.dir.join(path)));
+		let staged = match path_in_repo {
+			Some(staged) => staged,
+			None => self.dir.path().join(format!("{name}-{version}")),
+		};
+		if staged.exists() {
+			std::fs::remove_dir_all(&staged)
+				.with_context(|| format!("Cannot remove the old staged copy at {staged:?}"))?;
+		}
+
+		for file in cargo::list_package_files(&root, name)? {
+			let from = root.join(&file);
+			// Some files, like a readme from the workspace root, are pulled in from outside of
+			// the crate directory. Those are handled by pointing the manifest at them instead.
+			if GENERATED_FILES.iter().any(|g| file == Path::new(g)) || !from.is_file() {
+				continue
+			}
+			let to = staged.join(&file);
+			std::fs::create_dir_all(to.parent().expect("staged file has a parent; qed"))?;
+			std::fs::copy(&from, &to).with_context(|| format!("Cannot copy {from:?} to {to:?}"))?;
+		}
+
+		let mut toml = crate_details::read_toml(&root.join("Cargo.toml"))?;
+		let workspace_root = workspace_toml
+			.canonicalize()
+			.with_context(|| format!("Cannot find the workspace manifest {workspace_toml:?}"))?;
+		let workspace_root = workspace_root.parent().expect("manifest has a parent; qed");
+		crate_details::inherit_workspace_fields(
+			&mut toml,
+			&crate_details::read_toml(workspace_toml)?,
+			workspace_root,
+		)
+		.with_context(|| format!("Cannot fill in the values that {name} inherits"))?;
+		crate_details::strip_dev_dependencies(&mut toml);
+		crate_details::make_dependency_paths_absolute(&mut toml, &root);
+		if let Some(package) = toml.get_mut("package").and_then(|p| p.as_table_like_mut()) {
+			for field in PACKAGE_FILE_FIELDS {
+				if let Some(item) = package.get_mut(field) {
+					if let Some(relative) = item.as_str() {
+						let absolute = root.join(relative);
+						*item = toml_edit::value(absolute.to_string_lossy().into_owned());
+					}
+				}
+			}
+		}
+		// Don't let the staged crate be mistaken for part of any workspace that happens to be
+		// above the staging directory.
+		toml["workspace"] = toml_edit::table();
+
+		let toml_path = staged.join("Cargo.toml");
+		std::fs::write(&toml_path, toml.to_string())
+			.with_context(|| format!("Cannot save the staged Cargo.toml for {name}"))?;
+
+		Ok(staged)
+	}
+}
+
+impl StagingCleanup {
+	/// Remove the staging area, and tell git that its worktree has gone.
+	pub fn run(&self) {
+		remove_worktree(self.checkout.as_ref());
+		if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+			log::warn!("Cannot remove the staging directory at {:?}: {e}", self.dir);
+		}
+	}
+}
+
+impl Drop for StagingArea {
+	fn drop(&mut self) {
+		// The directory itself is removed along with the rest of the staging area, but git
+		// needs telling that the worktree has gone.
+		remove_worktree(self.checkout.as_ref());
+	}
+}
+
+fn remove_worktree(checkout: Option<&Checkout>) {
+	if let Some(checkout) = checkout {
+		if let Err(e) = git::remove_worktree(&checkout.repo, &checkout.dir) {
+			log::warn!("Cannot remove the staging worktree at {:?}: {e:?}", checkout.dir);
+		}
+	}
+}